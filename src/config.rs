@@ -0,0 +1,409 @@
+//! Settings of a mount, taken from the command line, the config file and the database
+//!
+//! The database stores the same settings as the config file, as text under the dotted path of
+//! their config file key (`rules.rmdir-untags` for `rmdir-untags` in the `[rules]` table). Lists
+//! are stored comma separated.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use fuser::MountOption;
+use itertools::Itertools as _;
+use serde::{Deserialize, Deserializer};
+
+use crate::{
+    error::{Error, Result},
+    filesystem::DEFAULT_IGNORE,
+    TagsFs, TagsFsDb,
+};
+
+/// Keys of all settings which can be stored in the database
+pub const KEYS: &[&str] = &[
+    "source",
+    "mountpoint",
+    "mount-options",
+    "log-level",
+    "rules.sync-xdg-tags",
+    "rules.rmdir-untags",
+    "rules.trash-retention-days",
+    "rules.ignore",
+];
+
+/// Longest time files may stay in the trash, about a hundred years
+pub const MAX_TRASH_RETENTION_DAYS: u64 = 36_525;
+
+/// Contents of the config file, settings which aren't given are `None`
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// Directory with the files which are tagged
+    pub source: Option<PathBuf>,
+    pub mountpoint: Option<PathBuf>,
+    /// Options as given to `mount -o`, e.g. `allow_other`
+    pub mount_options: Vec<String>,
+    /// Verbosity of logging, the same as passing `-v` that many times
+    ///
+    /// Logging is set up once, reloading the settings doesn't change this.
+    pub log_level: Option<usize>,
+    pub rules: Rules,
+}
+
+/// How the file system treats files and tags, these can change without remounting
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Rules {
+    pub sync_xdg_tags: Option<bool>,
+    pub rmdir_untags: Option<bool>,
+    /// At most [`MAX_TRASH_RETENTION_DAYS`]
+    #[serde(deserialize_with = "deserialize_retention_days")]
+    pub trash_retention_days: Option<u64>,
+    /// Replaces [`DEFAULT_IGNORE`]
+    pub ignore: Option<Vec<String>>,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// `$XDG_CONFIG_HOME/tagsfs/config.toml`, falling back to `~/.config`
+    pub fn default_path() -> Option<PathBuf> {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+            .map(|dir| dir.join("tagsfs").join("config.toml"))
+    }
+
+    /// The settings stored in `db`
+    pub fn from_db(db: &TagsFsDb) -> Result<Self> {
+        let mut config = Config::default();
+        for (key, value) in db.settings()? {
+            config.set(&key, &value)?;
+        }
+        Ok(config)
+    }
+
+    /// Set the setting `key` from its textual representation in the database
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let invalid = || Error::InvalidSetting(key.to_string(), value.to_string());
+        match key {
+            "source" => self.source = Some(value.into()),
+            "mountpoint" => self.mountpoint = Some(value.into()),
+            "mount-options" => self.mount_options = split_list(value),
+            "log-level" => self.log_level = Some(value.parse().map_err(|_| invalid())?),
+            "rules.sync-xdg-tags" => {
+                self.rules.sync_xdg_tags = Some(value.parse().map_err(|_| invalid())?)
+            }
+            "rules.rmdir-untags" => {
+                self.rules.rmdir_untags = Some(value.parse().map_err(|_| invalid())?)
+            }
+            "rules.trash-retention-days" => {
+                self.rules.trash_retention_days =
+                    Some(parse_retention_days(value).map_err(|_| invalid())?)
+            }
+            "rules.ignore" => self.rules.ignore = Some(split_list(value)),
+            _ => return Err(Error::UnknownSetting(key.to_string())),
+        }
+        Ok(())
+    }
+
+    /// Take the settings missing in `self` from `fallback`
+    ///
+    /// Mount options are the only exception, the ones of both are used unless one of `self`
+    /// [`replaces`] one of `fallback`.
+    pub fn or(self, fallback: Config) -> Config {
+        let replaced = |fallback: &String| {
+            let fallback = mount_option(fallback);
+            self.mount_options
+                .iter()
+                .any(|option| replaces(&mount_option(option), &fallback))
+        };
+        let fallback_options: Vec<_> = fallback
+            .mount_options
+            .into_iter()
+            .filter(|option| !replaced(option))
+            .collect();
+        Config {
+            source: self.source.or(fallback.source),
+            mountpoint: self.mountpoint.or(fallback.mountpoint),
+            mount_options: fallback_options
+                .into_iter()
+                .chain(self.mount_options)
+                .unique()
+                .collect(),
+            log_level: self.log_level.or(fallback.log_level),
+            rules: self.rules.or(fallback.rules),
+        }
+    }
+
+    /// Whether `mount_options` ask for a read-only mount
+    pub fn read_only(&self) -> bool {
+        self.mount_options.iter().any(|option| option == "ro")
+    }
+
+    /// `mount_options` as understood by fuser, named `tagsfs` unless they name it otherwise
+    pub fn mount_options(&self) -> Vec<MountOption> {
+        let name = MountOption::FSName("tagsfs".into());
+        let options: Vec<_> = self.mount_options.iter().map(|o| mount_option(o)).collect();
+        let named = options.iter().any(|option| replaces(option, &name));
+        (!named)
+            .then_some(name)
+            .into_iter()
+            .chain(options)
+            .collect()
+    }
+}
+
+impl Rules {
+    /// Take the rules missing in `self` from `fallback`
+    pub fn or(self, fallback: Rules) -> Rules {
+        Rules {
+            sync_xdg_tags: self.sync_xdg_tags.or(fallback.sync_xdg_tags),
+            rmdir_untags: self.rmdir_untags.or(fallback.rmdir_untags),
+            trash_retention_days: self.trash_retention_days.or(fallback.trash_retention_days),
+            ignore: self.ignore.or(fallback.ignore),
+        }
+    }
+
+    pub fn apply(&self, fs: &mut TagsFs) {
+        fs.xdg_sync = self.sync_xdg_tags.unwrap_or(false);
+        fs.rmdir_untags = self.rmdir_untags.unwrap_or(false);
+        fs.trash_retention = self
+            .trash_retention_days
+            .map(|days| Duration::from_secs(days.saturating_mul(24 * 60 * 60)));
+        fs.ignore = match &self.ignore {
+            Some(ignore) => ignore.clone(),
+            None => DEFAULT_IGNORE.iter().map(|p| p.to_string()).collect(),
+        };
+    }
+}
+
+/// Parse a single option of `mount -o`, options fuser doesn't know are passed on as is
+pub fn mount_option(option: &str) -> MountOption {
+    match option {
+        "ro" => MountOption::RO,
+        "rw" => MountOption::RW,
+        "allow_other" => MountOption::AllowOther,
+        "allow_root" => MountOption::AllowRoot,
+        "auto_unmount" => MountOption::AutoUnmount,
+        "default_permissions" => MountOption::DefaultPermissions,
+        "dev" => MountOption::Dev,
+        "nodev" => MountOption::NoDev,
+        "suid" => MountOption::Suid,
+        "nosuid" => MountOption::NoSuid,
+        "exec" => MountOption::Exec,
+        "noexec" => MountOption::NoExec,
+        "atime" => MountOption::Atime,
+        "noatime" => MountOption::NoAtime,
+        "dirsync" => MountOption::DirSync,
+        "sync" => MountOption::Sync,
+        "async" => MountOption::Async,
+        _ => match option.split_once('=') {
+            Some(("fsname", name)) => MountOption::FSName(name.into()),
+            Some(("subtype", subtype)) => MountOption::Subtype(subtype.into()),
+            _ => MountOption::CUSTOM(option.into()),
+        },
+    }
+}
+
+/// Whether `option` takes the place of `other` when both are given at different levels
+///
+/// An option replaces itself, its opposite like `rw` does `ro` and options of the same name with
+/// another value like `fsname=`.
+pub fn replaces(option: &MountOption, other: &MountOption) -> bool {
+    use MountOption::*;
+    let opposite = |a: &MountOption, b: &MountOption| {
+        matches!(
+            (a, b),
+            (RO, RW)
+                | (Dev, NoDev)
+                | (Suid, NoSuid)
+                | (Exec, NoExec)
+                | (Atime, NoAtime)
+                | (Sync, Async)
+                | (AllowOther, AllowRoot)
+        )
+    };
+    match (option, other) {
+        (FSName(_), FSName(_)) | (Subtype(_), Subtype(_)) => true,
+        (CUSTOM(option), CUSTOM(other)) => option.split('=').next() == other.split('=').next(),
+        _ => option == other || opposite(option, other) || opposite(other, option),
+    }
+}
+
+/// Parse a number of days files stay in the trash, rejecting more than
+/// [`MAX_TRASH_RETENTION_DAYS`]
+pub fn parse_retention_days(value: &str) -> std::result::Result<u64, String> {
+    let days = value.parse().map_err(|err| format!("{err}"))?;
+    if days > MAX_TRASH_RETENTION_DAYS {
+        return Err(format!("more than {MAX_TRASH_RETENTION_DAYS} days"));
+    }
+    Ok(days)
+}
+
+fn deserialize_retention_days<'de, D>(deserializer: D) -> std::result::Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<u64>::deserialize(deserializer)? {
+        Some(days) if days > MAX_TRASH_RETENTION_DAYS => Err(serde::de::Error::custom(format!(
+            "trash-retention-days is more than {MAX_TRASH_RETENTION_DAYS}"
+        ))),
+        days => Ok(days),
+    }
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_config_file() {
+        let config: Config = toml::from_str(
+            "source = \"/library\"\n\
+             mount-options = [\"allow_other\", \"ro\"]\n\
+             [rules]\n\
+             rmdir-untags = true\n\
+             ignore = [\"*.tmp\"]\n",
+        )
+        .unwrap();
+        assert_eq!(config.source, Some(PathBuf::from("/library")));
+        assert!(config.read_only());
+        assert!(config.mount_options().contains(&MountOption::AllowOther));
+        assert_eq!(config.rules.rmdir_untags, Some(true));
+        assert_eq!(config.rules.sync_xdg_tags, None);
+        assert_eq!(config.rules.ignore, Some(vec!["*.tmp".to_string()]));
+        assert!(toml::from_str::<Config>("unknown = 1").is_err());
+    }
+
+    #[test]
+    fn parses_database_settings() {
+        let mut config = Config::default();
+        config.set("mount-options", "allow_other, ro").unwrap();
+        config.set("rules.sync-xdg-tags", "false").unwrap();
+        config.set("rules.ignore", "*.tmp,,*~").unwrap();
+        assert_eq!(config.mount_options, ["allow_other", "ro"]);
+        assert_eq!(config.rules.sync_xdg_tags, Some(false));
+        assert_eq!(
+            config.rules.ignore,
+            Some(vec!["*.tmp".to_string(), "*~".to_string()])
+        );
+        assert!(config.set("rules.rmdir-untags", "maybe").is_err());
+        assert!(config.set("unknown", "1").is_err());
+    }
+
+    #[test]
+    fn earlier_settings_win() {
+        let overrides = Config {
+            mount_options: vec!["ro".into()],
+            rules: Rules {
+                sync_xdg_tags: Some(false),
+                ..Rules::default()
+            },
+            ..Config::default()
+        };
+        let fallback = Config {
+            source: Some("/library".into()),
+            mount_options: vec!["allow_other".into(), "ro".into()],
+            rules: Rules {
+                sync_xdg_tags: Some(true),
+                rmdir_untags: Some(true),
+                ..Rules::default()
+            },
+            ..Config::default()
+        };
+        let config = overrides.or(fallback);
+        assert_eq!(config.source, Some(PathBuf::from("/library")));
+        assert_eq!(config.rules.sync_xdg_tags, Some(false));
+        assert_eq!(config.rules.rmdir_untags, Some(true));
+        assert_eq!(config.mount_options, ["allow_other", "ro"]);
+    }
+
+    #[test]
+    fn mount_options_replace_their_counterparts() {
+        let layer = |options: &[&str]| Config {
+            mount_options: options.iter().map(|option| option.to_string()).collect(),
+            ..Config::default()
+        };
+        let command_line = layer(&["rw"]);
+        let config_file = layer(&["allow_root", "fsname=library", "max_read=4096"]);
+        let db = layer(&["ro", "allow_other", "noexec", "fsname=tags", "max_read=512"]);
+        let config = command_line.or(config_file).or(db);
+        assert_eq!(
+            config.mount_options,
+            [
+                "noexec",
+                "allow_root",
+                "fsname=library",
+                "max_read=4096",
+                "rw"
+            ]
+        );
+        assert!(!config.read_only());
+        assert_eq!(
+            config.mount_options()[..2],
+            [MountOption::NoExec, MountOption::AllowRoot]
+        );
+        assert!(!config
+            .mount_options()
+            .contains(&MountOption::FSName("tagsfs".into())));
+        assert!(layer(&[]).or(layer(&["ro"])).read_only());
+    }
+
+    #[test]
+    fn parses_mount_options() {
+        assert_eq!(mount_option("ro"), MountOption::RO);
+        assert_eq!(mount_option("allow_other"), MountOption::AllowOther);
+        assert_eq!(mount_option("noexec"), MountOption::NoExec);
+        assert_eq!(
+            mount_option("fsname=library"),
+            MountOption::FSName("library".into())
+        );
+        assert_eq!(
+            mount_option("subtype=tagsfs"),
+            MountOption::Subtype("tagsfs".into())
+        );
+        assert_eq!(
+            mount_option("max_read=4096"),
+            MountOption::CUSTOM("max_read=4096".into())
+        );
+        let config = Config {
+            mount_options: vec!["ro".into(), "allow_other".into()],
+            ..Config::default()
+        };
+        assert_eq!(
+            config.mount_options(),
+            [
+                MountOption::FSName("tagsfs".into()),
+                MountOption::RO,
+                MountOption::AllowOther
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_long_trash_retention() {
+        let mut config = Config::default();
+        config.set("rules.trash-retention-days", "30").unwrap();
+        assert_eq!(config.rules.trash_retention_days, Some(30));
+        assert!(config
+            .set("rules.trash-retention-days", &u64::MAX.to_string())
+            .is_err());
+        assert!(toml::from_str::<Config>("[rules]\ntrash-retention-days = 36526").is_err());
+        let config: Config = toml::from_str("[rules]\ntrash-retention-days = 7").unwrap();
+        assert_eq!(config.rules.trash_retention_days, Some(7));
+    }
+}
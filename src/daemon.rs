@@ -0,0 +1,133 @@
+//! Running the mount in the background and reacting to signals
+
+use std::{
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    mem,
+    os::unix::prelude::{AsRawFd, FromRawFd},
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use libc::c_int;
+
+use crate::error::{Error, Result};
+
+/// The background half of a fork, see [`Daemon::fork`]
+pub struct Daemon {
+    ready: File,
+}
+
+impl Daemon {
+    /// Fork into the background
+    ///
+    /// The calling process waits until the child reports with [`Daemon::ready`] and exits then.
+    /// It prints the error of [`Daemon::fail`] and exits with status 1 instead, or if the child
+    /// exits without reporting. This way errors while mounting still reach the terminal. The child
+    /// runs in a new session and keeps the working directory until it is ready.
+    ///
+    /// Nothing which doesn't survive a fork, like an SQLite connection, may be opened before.
+    pub fn fork() -> Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(Error::last_os_error());
+        }
+        let (read, write) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+        match unsafe { libc::fork() } {
+            -1 => Err(Error::last_os_error()),
+            0 => {
+                drop(read);
+                if unsafe { libc::setsid() } < 0 {
+                    return Err(Error::last_os_error());
+                }
+                Ok(Daemon { ready: write })
+            }
+            _ => {
+                drop(write);
+                // a status byte, followed by the error message if the child failed
+                let mut report = Vec::new();
+                let mut read = read;
+                let _ = read.read_to_end(&mut report);
+                match report.split_first() {
+                    Some((0, _)) => std::process::exit(0),
+                    Some((_, message)) if !message.is_empty() => {
+                        eprintln!("Error: {}", String::from_utf8_lossy(message));
+                        std::process::exit(1);
+                    }
+                    _ => std::process::exit(1),
+                }
+            }
+        }
+    }
+
+    /// Let the foreground process exit successfully and detach from the terminal
+    pub fn ready(mut self) -> Result<()> {
+        std::env::set_current_dir("/")?;
+        self.ready.write_all(&[0])?;
+        let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+        for fd in 0..3 {
+            if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Let the foreground process exit with `err` as error
+    pub fn fail(mut self, err: impl Display) -> Result<()> {
+        self.ready.write_all(format!("\x01{err}").as_bytes())?;
+        Ok(())
+    }
+}
+
+/// File with the id of this process, removed again when dropped
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        fs::write(&path, format!("{}\n", std::process::id()))?;
+        Ok(PidFile(path))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Signals which are only delivered through [`Signals::wait`]
+pub struct Signals(libc::sigset_t);
+
+impl Signals {
+    /// Block `signals` for this thread and every thread spawned by it afterwards
+    ///
+    /// This has to happen before the file system threads are started, otherwise those receive
+    /// the signals and the default action kills the process without unmounting.
+    pub fn block(signals: &[c_int]) -> Result<Self> {
+        let mut set: libc::sigset_t = unsafe { mem::zeroed() };
+        unsafe { libc::sigemptyset(&mut set) };
+        for &signal in signals {
+            if unsafe { libc::sigaddset(&mut set, signal) } != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        let err = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) };
+        if err != 0 {
+            return Err(Error::StdC(err));
+        }
+        Ok(Signals(set))
+    }
+
+    /// Wait for the next of the blocked signals
+    pub fn wait(&self) -> Result<c_int> {
+        let mut signal = 0;
+        let err = unsafe { libc::sigwait(&self.0, &mut signal) };
+        if err != 0 {
+            return Err(Error::StdC(err));
+        }
+        Ok(signal)
+    }
+}
@@ -1,9 +1,15 @@
 use std::{
-    collections::BTreeSet,
+    collections::{BTreeSet, HashMap, HashSet},
+    ffi::{CString, OsString},
+    mem,
+    os::unix::prelude::OsStrExt,
     path::{Path, PathBuf},
+    time::{Duration, SystemTime},
 };
 
-use rusqlite::{named_params, params, types::ValueRef, Connection, ToSql};
+use rusqlite::{
+    named_params, params, types::ValueRef, Connection, OpenFlags, OptionalExtension, ToSql,
+};
 
 use crate::{
     error::{Error, Result},
@@ -11,8 +17,66 @@ use crate::{
     Tag,
 };
 
+/// Tables added on top of the original layout, created on demand when opening a database, as
+/// name and column definitions
+const TABLES: [(&str, &str); 4] = [
+    (
+        "tag_attrs",
+        "tag_id INTEGER PRIMARY KEY, mode INTEGER, uid INTEGER, gid INTEGER, crtime INTEGER, \
+         mtime INTEGER, ctime INTEGER",
+    ),
+    ("settings", "key TEXT PRIMARY KEY, value TEXT NOT NULL"),
+    ("origins", "file TEXT PRIMARY KEY, path TEXT NOT NULL"),
+    ("trash", "file TEXT PRIMARY KEY, name TEXT, deleted INTEGER"),
+];
+
+/// Metadata of a tag directory, `None` falls back to the attributes of the source directory
+///
+/// Times are stored as nanoseconds since the unix epoch.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TagAttr {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub crtime: Option<SystemTime>,
+    pub mtime: Option<SystemTime>,
+    pub ctime: Option<SystemTime>,
+}
+
+/// Tables of the original layout, which tagsfs expects to exist already
+const BASE_TABLES: [(&str, &str); 3] = [
+    ("tags", "id INTEGER PRIMARY KEY, tag TEXT NOT NULL UNIQUE"),
+    (
+        "file_tags",
+        "file TEXT NOT NULL, tag_id INTEGER NOT NULL, UNIQUE (file, tag_id)",
+    ),
+    (
+        "inodes",
+        "id INTEGER PRIMARY KEY, discriminant TEXT NOT NULL, data TEXT NOT NULL",
+    ),
+];
+
+/// `CREATE TABLE` statements for the tables of the original layout
+#[cfg(test)]
+pub(crate) fn base_schema() -> String {
+    schema(&BASE_TABLES)
+}
+
+/// `CREATE TABLE` statements for `tables` which don't exist yet
+fn schema(tables: &[(&str, &str)]) -> String {
+    tables
+        .iter()
+        .map(|(table, columns)| format!("CREATE TABLE IF NOT EXISTS {table} ({columns});"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// `user_version` of databases whose `config` and `options` tables moved to `settings`
+const SETTINGS_VERSION: u32 = 1;
+
 pub struct TagsFsDb {
     conn: Connection,
+    read_only: bool,
 }
 
 impl TagsFsDb {
@@ -20,15 +84,168 @@ impl TagsFsDb {
     where
         P: AsRef<Path>,
     {
-        Ok(Self {
-            conn: Connection::open(p)?,
-        })
+        let conn = Connection::open(p)?;
+        // the command line tools may change the database while it is mounted
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch(&schema(&TABLES))?;
+        let db = Self {
+            conn,
+            read_only: false,
+        };
+        db.transaction(|db| db.migrate_settings())?;
+        Ok(db)
+    }
+
+    /// Open `p` without ever writing to it
+    ///
+    /// Nothing is created or migrated in the file, temporary tables stand in for what
+    /// [`TagsFsDb::new`] would add, so databases of an older layout can be read as well. On
+    /// read-only media the file is opened as immutable, since SQLite can't even take its locks
+    /// there. Inodes handed out for entries which didn't have one yet are only kept in memory.
+    pub fn open_read_only<P>(p: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let p = p.as_ref();
+        let flags = OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+        let conn = if on_read_only_media(p) {
+            Connection::open_with_flags(
+                format!("file:{}?immutable=1", uri_escape(p)),
+                flags | OpenFlags::SQLITE_OPEN_URI,
+            )?
+        } else {
+            Connection::open_with_flags(p, flags)?
+        };
+        conn.busy_timeout(Duration::from_secs(5))?;
+        let db = Self {
+            conn,
+            read_only: true,
+        };
+        db.shadow_tables()?;
+        Ok(db)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Temporary tables for all tables which are written to or missing in the file
+    ///
+    /// A temporary table shadows the one in the file for all unqualified queries.
+    fn shadow_tables(&self) -> Result<()> {
+        self.conn.execute_batch("PRAGMA temp_store = MEMORY")?;
+        let mut old_settings = Vec::new();
+        if self.user_version()? < SETTINGS_VERSION {
+            for table in ["config", "options"] {
+                if self.has_table(table)? {
+                    old_settings.push(table);
+                }
+            }
+        }
+        for (table, columns) in BASE_TABLES.iter().chain(&TABLES) {
+            let exists = self.has_table(table)?;
+            // new inodes are written on every lookup, settings are merged with the old tables
+            let copied = *table == "inodes" || (*table == "settings" && !old_settings.is_empty());
+            if exists && !copied {
+                continue;
+            }
+            self.conn
+                .execute_batch(&format!("CREATE TEMP TABLE {table} ({columns})"))?;
+            if exists {
+                self.conn.execute_batch(&format!(
+                    "INSERT INTO temp.{table} SELECT * FROM main.{table}"
+                ))?;
+            }
+        }
+        for table in old_settings {
+            self.conn.execute_batch(&format!(
+                "INSERT OR IGNORE INTO temp.settings (key, value) \
+                 SELECT key, value FROM main.{table}"
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// `user_version` counts the migrations which already ran on this database
+    fn user_version(&self) -> Result<u32> {
+        Ok(self
+            .conn
+            .query_row("PRAGMA main.user_version", [], |row| row.get(0))?)
+    }
+
+    /// Whether the file has a table named `table`, temporary ones don't count
+    fn has_table(&self, table: &str) -> Result<bool> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT 1 FROM main.sqlite_master WHERE type = 'table' AND name = ?")?
+            .exists([table])?)
+    }
+
+    /// Move the settings of the former `config` and `options` tables into `settings`
+    fn migrate_settings(&self) -> Result<()> {
+        if self.user_version()? >= SETTINGS_VERSION {
+            return Ok(());
+        }
+        for table in ["config", "options"] {
+            if self.has_table(table)? {
+                self.conn.execute_batch(&format!(
+                    "INSERT OR IGNORE INTO settings (key, value) SELECT key, value FROM {table}; \
+                     DROP TABLE {table};"
+                ))?;
+            }
+        }
+        self.conn
+            .execute_batch(&format!("PRAGMA user_version = {SETTINGS_VERSION}"))?;
+        Ok(())
+    }
+
+    pub fn setting(&self, key: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT value FROM settings WHERE key = ?")?
+            .query_row([key], |row| row.get(0))
+            .optional()?)
+    }
+
+    pub fn set_setting(&self, key: &str, value: &str) -> Result<()> {
+        self.conn
+            .prepare_cached("INSERT OR REPLACE INTO settings (key, value) VALUES (?, ?)")?
+            .execute([key, value])?;
+        Ok(())
+    }
+
+    /// Remove the setting `key`, returns whether it was set
+    pub fn remove_setting(&self, key: &str) -> Result<bool> {
+        Ok(self
+            .conn
+            .prepare_cached("DELETE FROM settings WHERE key = ?")?
+            .execute([key])?
+            > 0)
+    }
+
+    pub fn settings(&self) -> Result<Vec<(String, String)>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT key, value FROM settings ORDER BY key")?;
+        let settings = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(settings)
+    }
+
+    /// Run `f` in a single transaction which is rolled back if `f` fails
+    pub fn transaction<T>(&self, f: impl FnOnce(&Self) -> Result<T>) -> Result<T> {
+        let tx = self.conn.unchecked_transaction()?;
+        let result = f(self)?;
+        tx.commit()?;
+        Ok(result)
     }
 
     pub fn mountpoint(&self) -> Result<PathBuf> {
-        Ok(self.conn
-            .prepare("SELECT value FROM config WHERE key = 'mountpoint'")?
-            .query_row([], |r| r.get::<_, String>(0))?.into())
+        Ok(self
+            .setting("mountpoint")?
+            .ok_or(rusqlite::Error::QueryReturnedNoRows)?
+            .into())
     }
 
     pub fn sub_tags(&self, tags: &BTreeSet<Tag>) -> Result<Vec<Tag>> {
@@ -47,6 +264,67 @@ impl TagsFsDb {
         Ok(sub_tags)
     }
 
+    /// Every file with each of its tags, except for the files in the trash
+    pub fn all_file_tags(&self) -> Result<Vec<(String, Tag)>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT file, tag \
+                 FROM file_tags \
+                 JOIN tags \
+                 ON file_tags.tag_id = tags.id \
+                 WHERE file NOT IN (SELECT file FROM trash) \
+                 ORDER BY file, tag",
+        )?;
+        let file_tags = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(file_tags)
+    }
+
+    /// Every file which was imported from elsewhere with the path it was imported from, except
+    /// for the files in the trash
+    pub fn origins(&self) -> Result<Vec<(String, String)>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT file, path \
+                 FROM origins \
+                 WHERE file NOT IN (SELECT file FROM trash) \
+                 ORDER BY file",
+        )?;
+        let origins = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(origins)
+    }
+
+    /// Remove all tags, origins and settings, files in the trash stay there without tags
+    pub fn clear(&self) -> Result<()> {
+        self.conn.execute_batch(
+            "DELETE FROM file_tags; \
+             DELETE FROM tags; \
+             DELETE FROM tag_attrs; \
+             DELETE FROM settings; \
+             DELETE FROM origins WHERE file NOT IN (SELECT file FROM trash); \
+             DELETE FROM inodes WHERE discriminant = 'tags' AND data != '';",
+        )?;
+        Ok(())
+    }
+
+    /// All tags with the number of files carrying them, files in the trash aren't counted
+    pub fn tag_counts(&self) -> Result<Vec<(Tag, u64)>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT tag, COUNT(file) \
+                 FROM tags \
+                 LEFT JOIN file_tags \
+                 ON file_tags.tag_id = tags.id \
+                 AND file NOT IN (SELECT file FROM trash) \
+                 GROUP BY tags.id \
+                 ORDER BY tag",
+        )?;
+        let counts = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(counts)
+    }
+
     pub fn file_tags(&self, filename: impl ToSql) -> Result<BTreeSet<String>> {
         let mut stmt = self.conn.prepare_cached(
             "SELECT DISTINCT tag \
@@ -61,6 +339,70 @@ impl TagsFsDb {
         Ok(tags)
     }
 
+    /// All files which carry every tag in `tags`, except for the ones in the trash
+    pub fn files_with_tags(&self, tags: &BTreeSet<Tag>) -> Result<HashSet<String>> {
+        let mut stmt = self.conn.prepare_cached(
+            format!(
+                "SELECT file \
+                 FROM file_tags \
+                 JOIN tags \
+                 ON file_tags.tag_id = tags.id \
+                 WHERE tag IN ({}) \
+                 AND file NOT IN (SELECT file FROM trash) \
+                 GROUP BY file \
+                 HAVING COUNT(DISTINCT tag) = {}",
+                vec!["?"; tags.len()].join(", "),
+                tags.len(),
+            )
+            .as_str(),
+        )?;
+        let files = stmt
+            .query_map(rusqlite::params_from_iter(tags.iter()), |row| row.get(0))?
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(files)
+    }
+
+    /// For every tag, the number of files which carry it in addition to all of `tags`
+    ///
+    /// Trashed files aren't counted and tags without any such file are left out.
+    pub fn sub_tag_counts(&self, tags: &BTreeSet<Tag>) -> Result<HashMap<Tag, u64>> {
+        let with_tags = if tags.is_empty() {
+            String::new()
+        } else {
+            format!(
+                "AND file IN (\
+                     SELECT file \
+                     FROM file_tags \
+                     JOIN tags \
+                     ON file_tags.tag_id = tags.id \
+                     WHERE tag IN ({}) \
+                     GROUP BY file \
+                     HAVING COUNT(DISTINCT tag) = {}\
+                 )",
+                vec!["?"; tags.len()].join(", "),
+                tags.len(),
+            )
+        };
+        let mut stmt = self.conn.prepare_cached(
+            format!(
+                "SELECT tag, COUNT(DISTINCT file) \
+                 FROM file_tags \
+                 JOIN tags \
+                 ON file_tags.tag_id = tags.id \
+                 WHERE file NOT IN (SELECT file FROM trash) \
+                 {with_tags} \
+                 GROUP BY tags.id"
+            )
+            .as_str(),
+        )?;
+        let counts = stmt
+            .query_map(rusqlite::params_from_iter(tags.iter()), |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(counts)
+    }
+
     pub fn remove_tags_from_file<I, It>(&self, tags: I, file: impl ToSql) -> Result<()>
     where
         I: IntoIterator<Item = It>,
@@ -74,6 +416,7 @@ impl TagsFsDb {
                 self.conn
                     .query_row("SELECT id FROM tags WHERE tag = ?", [tag], |r| r.get(0))?;
             stmt.execute(params![tag_id, file])?;
+            self.touch_tag(tag_id)?;
         }
         Ok(())
     }
@@ -88,10 +431,194 @@ impl TagsFsDb {
             self.conn
                 .prepare_cached("INSERT INTO file_tags (file, tag_id) VALUES (?, ?)")?
                 .insert(params![file, tag_id])?;
+            self.touch_tag(tag_id)?;
+        }
+        Ok(())
+    }
+
+    /// Replace all tags of `file` with `tags`
+    pub fn set_file_tags(&self, tags: &BTreeSet<Tag>, file: &str) -> Result<()> {
+        let current = self.file_tags(file)?;
+        self.remove_tags_from_file(current.difference(tags), file)?;
+        self.add_tags_to_file(tags.difference(&current), file)
+    }
+
+    /// Record that the backing file `from` was renamed to `to`, keeping its tags and inode
+    pub fn rename_file(&self, from: &str, to: &str) -> Result<()> {
+        self.conn
+            .prepare_cached("UPDATE file_tags SET file = ? WHERE file = ?")?
+            .execute([to, from])?;
+        self.conn
+            .prepare_cached("UPDATE origins SET file = ? WHERE file = ?")?
+            .execute([to, from])?;
+        self.conn
+            .prepare_cached("UPDATE inodes SET data = ? WHERE discriminant = 'file' AND data = ?")?
+            .execute([to, from])?;
+        // moving in and out of the trash is a rename as well
+        self.touch_file_tags(to)
+    }
+
+    /// Record that the backing file `from` was renamed over `to`
+    ///
+    /// The file keeps the inode of `from`, which is what the kernel knows it by, and gets the
+    /// tags of both files.
+    pub fn replace_file(&self, from: &str, to: &str) -> Result<()> {
+        self.conn
+            .prepare_cached("DELETE FROM inodes WHERE discriminant = 'file' AND data = ?")?
+            .execute([to])?;
+        self.conn
+            .prepare_cached(
+                "UPDATE file_tags SET file = ?2 WHERE file = ?1 \
+                 AND tag_id NOT IN (SELECT tag_id FROM file_tags WHERE file = ?2)",
+            )?
+            .execute([from, to])?;
+        self.conn
+            .prepare_cached("DELETE FROM file_tags WHERE file = ?")?
+            .execute([from])?;
+        self.conn
+            .prepare_cached("DELETE FROM origins WHERE file = ?")?
+            .execute([from])?;
+        self.conn
+            .prepare_cached("UPDATE inodes SET data = ? WHERE discriminant = 'file' AND data = ?")?
+            .execute([to, from])?;
+        self.touch_file_tags(to)
+    }
+
+    /// Remember where `file` was before it was imported into `source`
+    pub fn set_origin(&self, file: &str, path: &str) -> Result<()> {
+        self.conn
+            .prepare_cached("INSERT OR REPLACE INTO origins (file, path) VALUES (?, ?)")?
+            .execute([file, path])?;
+        Ok(())
+    }
+
+    pub fn origin(&self, file: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT path FROM origins WHERE file = ?")?
+            .query_row([file], |row| row.get(0))
+            .optional()?)
+    }
+
+    /// The file which was imported from `path`
+    pub fn file_with_origin(&self, path: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT file FROM origins WHERE path = ?")?
+            .query_row([path], |row| row.get(0))
+            .optional()?)
+    }
+
+    /// Swap tags and inodes of the backing files `a` and `b` after they were exchanged
+    pub fn exchange_files(&self, a: &str, b: &str) -> Result<()> {
+        // `/` can't be part of a file name so this can't clash with a real file
+        let tmp = format!("{a}/{b}");
+        self.rename_file(a, &tmp)?;
+        self.rename_file(b, a)?;
+        self.rename_file(&tmp, b)
+    }
+
+    /// Drop tags and inode of a backing file which doesn't exist anymore
+    pub fn forget_file(&self, file: &str) -> Result<()> {
+        self.conn
+            .prepare_cached("DELETE FROM file_tags WHERE file = ?")?
+            .execute([file])?;
+        self.conn
+            .prepare_cached("DELETE FROM origins WHERE file = ?")?
+            .execute([file])?;
+        self.conn
+            .prepare_cached("DELETE FROM inodes WHERE discriminant = 'file' AND data = ?")?
+            .execute([file])?;
+        Ok(())
+    }
+
+    /// Rename the tag `from` to `to`, if `to` already exists both tags are merged
+    ///
+    /// Inodes of tag sets which include `from` are moved to the renamed set so they stay valid.
+    pub fn rename_tag(&self, from: &str, to: &str) -> Result<()> {
+        let from_id = self.tag_id(from)?;
+        match self.tag_id(to) {
+            Ok(to_id) => {
+                self.conn
+                    .prepare_cached(
+                        "INSERT INTO file_tags (file, tag_id) \
+                         SELECT file, ?2 FROM file_tags WHERE tag_id = ?1 \
+                         AND file NOT IN (SELECT file FROM file_tags WHERE tag_id = ?2)",
+                    )?
+                    .execute([from_id, to_id])?;
+                self.delete_tags(&BTreeSet::from([from.to_string()]))?;
+                self.touch_tag(to_id)?;
+            }
+            Err(_) => {
+                self.conn
+                    .prepare_cached("UPDATE tags SET tag = ? WHERE id = ?")?
+                    .execute(params![to, from_id])?;
+                self.touch_tag(from_id)?;
+            }
         }
+
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT id FROM inodes WHERE discriminant = 'tags'")?;
+        let inodes = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<std::result::Result<Vec<u64>, _>>()?;
+        for ino in inodes {
+            let mut tags = match self.entry(ino)? {
+                Entry::Tags(tags) if tags.contains(from) => tags,
+                _ => continue,
+            };
+            tags.remove(from);
+            tags.insert(to.to_string());
+            let renamed = Entry::Tags(tags);
+            if self.inode(&renamed).is_ok() {
+                // merged into a set which already has an inode
+                self.conn
+                    .prepare_cached("DELETE FROM inodes WHERE id = ?")?
+                    .execute([ino])?;
+            } else {
+                let (_, data) = renamed.discrimimant_data();
+                self.conn
+                    .prepare_cached("UPDATE inodes SET data = ? WHERE id = ?")?
+                    .execute(params![data, ino])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remember that `file` is the trashed file which used to be called `name`
+    pub fn add_trashed(&self, file: &str, name: &str) -> Result<()> {
+        self.conn
+            .prepare_cached("INSERT INTO trash (file, name, deleted) VALUES (?, ?, ?)")?
+            .execute(params![file, name, to_nanos(SystemTime::now())])?;
         Ok(())
     }
 
+    pub fn remove_trashed(&self, file: &str) -> Result<()> {
+        self.conn
+            .prepare_cached("DELETE FROM trash WHERE file = ?")?
+            .execute([file])?;
+        Ok(())
+    }
+
+    pub fn is_trashed(&self, file: &str) -> Result<bool> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT file FROM trash WHERE file = ?")?
+            .exists([file])?)
+    }
+
+    /// Trashed files which were deleted before `time`
+    pub fn trashed_before(&self, time: SystemTime) -> Result<Vec<String>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT file FROM trash WHERE deleted < ?")?;
+        let files = stmt
+            .query_map([to_nanos(time)], |row| row.get(0))?
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(files)
+    }
+
     pub fn delete_tags(&self, tags: &BTreeSet<Tag>) -> Result<()> {
         for tag in tags {
             let tag_id: u64 =
@@ -103,6 +630,9 @@ impl TagsFsDb {
             self.conn
                 .prepare_cached("DELETE FROM file_tags WHERE tag_id = ?")?
                 .execute([tag_id])?;
+            self.conn
+                .prepare_cached("DELETE FROM tag_attrs WHERE tag_id = ?")?
+                .execute([tag_id])?;
         }
         Ok(())
     }
@@ -120,6 +650,33 @@ impl TagsFsDb {
             })? as u64)
     }
 
+    /// Inodes of all files indexed by file name
+    pub fn file_inodes(&self) -> Result<HashMap<OsString, u64>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT id, data FROM inodes WHERE discriminant = 'file'")?;
+        let inodes = stmt
+            .query_map([], |row| {
+                Ok((
+                    OsString::from(row.get::<_, String>("data")?),
+                    row.get("id")?,
+                ))
+            })?
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(inodes)
+    }
+
+    /// Inodes of all tag directories indexed by their tags joined with `/`
+    pub fn tag_inodes(&self) -> Result<HashMap<String, u64>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT id, data FROM inodes WHERE discriminant = 'tags'")?;
+        let inodes = stmt
+            .query_map([], |row| Ok((row.get("data")?, row.get("id")?)))?
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(inodes)
+    }
+
     pub fn inode(&self, entry: &Entry) -> Result<u64> {
         let (discriminant, data) = entry.discrimimant_data();
         let mut stmt = self.conn.prepare_cached(
@@ -149,6 +706,7 @@ impl TagsFsDb {
                         .collect(),
                 )),
                 ValueRef::Text(b"file") => Ok(Entry::File(data.into())),
+                ValueRef::Text(b"trash") => Ok(Entry::Trash),
                 _ => Err(Error::InvalidEntryDiscriminant),
             })
         })??;
@@ -157,10 +715,9 @@ impl TagsFsDb {
 
     pub fn source(&self) -> Result<PathBuf> {
         Ok(self
-            .conn
-            .prepare("SELECT value FROM options WHERE key = 'source'")?
-            .query_row([], |row| row.get::<_, String>(0))
-            .map(PathBuf::from)?)
+            .setting("source")?
+            .ok_or(rusqlite::Error::QueryReturnedNoRows)?
+            .into())
     }
 
     pub fn inode_or_create(&self, entry: &Entry) -> Result<u64> {
@@ -168,10 +725,155 @@ impl TagsFsDb {
     }
 
     pub fn create_tag(&self, tag: impl ToSql) -> Result<u64> {
-        Ok(self
+        let tag_id = self
             .conn
             .prepare_cached("INSERT INTO tags (tag) VALUES (?)")?
-            .insert([tag])? as u64)
+            .insert([tag])? as u64;
+        let now = to_nanos(SystemTime::now());
+        self.conn
+            .prepare_cached(
+                "INSERT INTO tag_attrs (tag_id, crtime, mtime, ctime) VALUES (?, ?, ?, ?)",
+            )?
+            .execute(params![tag_id, now, now, now])?;
+        Ok(tag_id)
+    }
+
+    pub fn tag_attr(&self, tag: impl ToSql) -> Result<TagAttr> {
+        let tag_id = self.tag_id(tag)?;
+        let attr = self
+            .conn
+            .prepare_cached("SELECT * FROM tag_attrs WHERE tag_id = ?")?
+            .query_row([tag_id], |row| {
+                Ok(TagAttr {
+                    mode: row.get("mode")?,
+                    uid: row.get("uid")?,
+                    gid: row.get("gid")?,
+                    crtime: row.get::<_, Option<i64>>("crtime")?.map(from_nanos),
+                    mtime: row.get::<_, Option<i64>>("mtime")?.map(from_nanos),
+                    ctime: row.get::<_, Option<i64>>("ctime")?.map(from_nanos),
+                })
+            })
+            .optional()?;
+        Ok(attr.unwrap_or_default())
+    }
+
+    /// The attributes of every tag, tags without stored attributes are left out
+    pub fn tag_attrs(&self) -> Result<HashMap<Tag, TagAttr>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT tag, mode, uid, gid, crtime, mtime, ctime \
+                 FROM tags \
+                 JOIN tag_attrs \
+                 ON tag_attrs.tag_id = tags.id",
+        )?;
+        let attrs = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get("tag")?,
+                    TagAttr {
+                        mode: row.get("mode")?,
+                        uid: row.get("uid")?,
+                        gid: row.get("gid")?,
+                        crtime: row.get::<_, Option<i64>>("crtime")?.map(from_nanos),
+                        mtime: row.get::<_, Option<i64>>("mtime")?.map(from_nanos),
+                        ctime: row.get::<_, Option<i64>>("ctime")?.map(from_nanos),
+                    },
+                ))
+            })?
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(attrs)
+    }
+
+    /// Change permissions, owner or modification time of `tag`, `None` keeps the current value
+    pub fn set_tag_attr(
+        &self,
+        tag: impl ToSql,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        mtime: Option<SystemTime>,
+    ) -> Result<()> {
+        let tag_id = self.tag_id(tag)?;
+        self.conn
+            .prepare_cached(
+                "INSERT INTO tag_attrs (tag_id, mode, uid, gid, mtime, ctime) \
+                 VALUES (:tag_id, :mode, :uid, :gid, :mtime, :now) \
+                 ON CONFLICT (tag_id) DO UPDATE SET \
+                 mode = COALESCE(:mode, mode), \
+                 uid = COALESCE(:uid, uid), \
+                 gid = COALESCE(:gid, gid), \
+                 mtime = COALESCE(:mtime, mtime), \
+                 ctime = :now",
+            )?
+            .execute(named_params! {
+                ":tag_id": tag_id,
+                ":mode": mode,
+                ":uid": uid,
+                ":gid": gid,
+                ":mtime": mtime.map(to_nanos),
+                ":now": to_nanos(SystemTime::now()),
+            })?;
+        Ok(())
+    }
+
+    /// Overwrite the metadata of `tag` with the attributes `attr` has, `None` keeps the current
+    /// value
+    pub fn write_tag_attr(&self, tag: impl ToSql, attr: &TagAttr) -> Result<()> {
+        let tag_id = self.tag_id(tag)?;
+        self.conn
+            .prepare_cached(
+                "INSERT INTO tag_attrs (tag_id, mode, uid, gid, crtime, mtime, ctime) \
+                 VALUES (:tag_id, :mode, :uid, :gid, :crtime, :mtime, :ctime) \
+                 ON CONFLICT (tag_id) DO UPDATE SET \
+                 mode = COALESCE(:mode, mode), \
+                 uid = COALESCE(:uid, uid), \
+                 gid = COALESCE(:gid, gid), \
+                 crtime = COALESCE(:crtime, crtime), \
+                 mtime = COALESCE(:mtime, mtime), \
+                 ctime = COALESCE(:ctime, ctime)",
+            )?
+            .execute(named_params! {
+                ":tag_id": tag_id,
+                ":mode": attr.mode,
+                ":uid": attr.uid,
+                ":gid": attr.gid,
+                ":crtime": attr.crtime.map(to_nanos),
+                ":mtime": attr.mtime.map(to_nanos),
+                ":ctime": attr.ctime.map(to_nanos),
+            })?;
+        Ok(())
+    }
+
+    /// Bump modification and change time of a tag whose set of files changed
+    fn touch_tag(&self, tag_id: u64) -> Result<()> {
+        let now = to_nanos(SystemTime::now());
+        self.conn
+            .prepare_cached(
+                "INSERT INTO tag_attrs (tag_id, mtime, ctime) VALUES (?1, ?2, ?2) \
+                 ON CONFLICT (tag_id) DO UPDATE SET mtime = ?2, ctime = ?2",
+            )?
+            .execute(params![tag_id, now])?;
+        Ok(())
+    }
+
+    /// Bump the tags of `file`, whose name changed or which appeared or disappeared
+    fn touch_file_tags(&self, file: &str) -> Result<()> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT tag_id FROM file_tags WHERE file = ?")?;
+        let tag_ids = stmt
+            .query_map([file], |row| row.get(0))?
+            .collect::<std::result::Result<Vec<u64>, _>>()?;
+        for tag_id in tag_ids {
+            self.touch_tag(tag_id)?;
+        }
+        Ok(())
+    }
+
+    pub fn tag_count(&self) -> Result<u64> {
+        Ok(self
+            .conn
+            .prepare_cached("SELECT COUNT(*) FROM tags")?
+            .query_row([], |r| r.get(0))?)
     }
 
     pub fn tag_id(&self, tag: impl ToSql) -> Result<u64> {
@@ -181,3 +883,97 @@ impl TagsFsDb {
             .query_row([tag], |r| r.get(0))?)
     }
 }
+
+pub(crate) fn to_nanos(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as i64
+}
+
+pub(crate) fn from_nanos(nanos: i64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos as u64)
+}
+
+/// Whether the file system of `path` is mounted read-only
+fn on_read_only_media(path: &Path) -> bool {
+    let path = match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    unsafe { libc::statvfs(path.as_ptr(), &mut stat) == 0 && stat.f_flag & libc::ST_RDONLY != 0 }
+}
+
+/// `path` with the characters which have a meaning in an SQLite URI escaped
+fn uri_escape(path: &Path) -> String {
+    let mut escaped = String::new();
+    for &byte in path.as_os_str().as_bytes() {
+        match byte {
+            b'?' | b'#' | b'%' | b'\0'..=b' ' | 0x7f.. => escaped.push_str(&format!("%{byte:02X}")),
+            _ => escaped.push(byte as char),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl TagsFsDb {
+        /// An empty database in memory
+        pub(crate) fn in_memory() -> Self {
+            let conn = Connection::open_in_memory().unwrap();
+            conn.execute_batch(&base_schema()).unwrap();
+            conn.execute_batch(&schema(&TABLES)).unwrap();
+            let db = Self {
+                conn,
+                read_only: false,
+            };
+            db.transaction(|db| db.migrate_settings()).unwrap();
+            db
+        }
+    }
+
+    fn tags(tags: &[&str]) -> BTreeSet<Tag> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn settings_are_migrated_once() {
+        let db = TagsFsDb::in_memory();
+        let old_table = "CREATE TABLE config (key TEXT, value TEXT); \
+                         INSERT INTO config VALUES ('source', '/old');";
+        db.conn.execute_batch(old_table).unwrap();
+        db.conn.execute_batch("PRAGMA user_version = 0").unwrap();
+        db.migrate_settings().unwrap();
+        assert_eq!(db.setting("source").unwrap().as_deref(), Some("/old"));
+
+        db.remove_setting("source").unwrap();
+        db.conn.execute_batch(old_table).unwrap();
+        db.migrate_settings().unwrap();
+        assert_eq!(db.setting("source").unwrap(), None);
+    }
+
+    #[test]
+    fn sub_tag_counts_match_files_with_tags() {
+        let db = TagsFsDb::in_memory();
+        db.add_tags_to_file(["a", "b"], "one").unwrap();
+        db.add_tags_to_file(["a", "c"], "two").unwrap();
+        db.add_tags_to_file(["a", "b", "c"], "three").unwrap();
+        db.add_tags_to_file(["b"], "four").unwrap();
+        for dir in [tags(&[]), tags(&["a"]), tags(&["a", "b"])] {
+            let counts = db.sub_tag_counts(&dir).unwrap();
+            for tag in db.sub_tags(&dir).unwrap() {
+                let mut sub_dir = dir.clone();
+                sub_dir.insert(tag.clone());
+                let files = db.files_with_tags(&sub_dir).unwrap().len() as u64;
+                assert_eq!(
+                    counts.get(&tag).copied().unwrap_or(0),
+                    files,
+                    "{dir:?} {tag}"
+                );
+            }
+        }
+    }
+}
@@ -10,4 +10,42 @@ pub enum Error {
     IoError(#[from] std::io::Error),
     #[error("file system error")]
     StdC(i32),
+    #[error("invalid config file")]
+    Config(#[from] toml::de::Error),
+    #[error("unknown setting {0:?}")]
+    UnknownSetting(String),
+    #[error("invalid value {1:?} for setting {0:?}")]
+    InvalidSetting(String, String),
+    #[error("setting {0:?} is required")]
+    MissingSetting(String),
+    #[error("invalid export: {0}")]
+    InvalidExport(String),
+    #[error("json error")]
+    Json(#[from] serde_json::Error),
+    #[error("csv error")]
+    Csv(#[from] csv::Error),
+}
+
+impl Error {
+    /// The errno to reply to the kernel with
+    pub fn errno(&self) -> i32 {
+        match self {
+            Error::StdC(errno) => *errno,
+            Error::IoError(err) => err.raw_os_error().unwrap_or(libc::EIO),
+            Error::Database(rusqlite::Error::QueryReturnedNoRows) => libc::ENOENT,
+            Error::Database(_) | Error::InvalidEntryDiscriminant => libc::ENODEV,
+            Error::Config(_)
+            | Error::UnknownSetting(_)
+            | Error::InvalidSetting(..)
+            | Error::MissingSetting(_)
+            | Error::InvalidExport(_)
+            | Error::Json(_)
+            | Error::Csv(_) => libc::EINVAL,
+        }
+    }
+
+    /// Capture `errno` after a failed libc call
+    pub fn last_os_error() -> Self {
+        Error::IoError(std::io::Error::last_os_error())
+    }
 }
@@ -0,0 +1,436 @@
+//! Export and import of the whole tag database as JSON or CSV
+//!
+//! Both formats carry the same data, format version [`VERSION`]. Files in the trash aren't
+//! exported. Times are nanoseconds since the unix epoch.
+//!
+//! JSON is a single object:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "files": [{ "name": "rome.jpg", "tags": ["italy", "2019"],
+//!               "origin": "/photos/2019/Italy/rome.jpg" }],
+//!   "tags": [{ "name": "italy", "mode": 493, "uid": 1000, "gid": 1000,
+//!              "crtime": 1646000000000000000, "mtime": 1646000000000000000,
+//!              "ctime": 1646000000000000000 }],
+//!   "settings": { "source": "/home/me/library" }
+//! }
+//! ```
+//!
+//! Attributes of a tag which aren't set are left out, as is the origin of files which weren't
+//! brought in by a tree import.
+//!
+//! CSV has a header and the columns `kind,name,field,value`, one row per fact:
+//!
+//! | kind      | name       | field     | value              |
+//! |-----------|------------|-----------|--------------------|
+//! | `version` |            |           | `1`                |
+//! | `tag`     | `italy`    |           |                    |
+//! | `tag`     | `italy`    | `mode`    | `493`              |
+//! | `file`    | `rome.jpg` |           | `italy`            |
+//! | `file`    | `rome.jpg` | `origin`  | `/photos/rome.jpg` |
+//! | `setting` | `source`   |           | `/home/me/lib`     |
+//!
+//! Tag rows with a `field` set one of the attributes `mode`, `uid`, `gid`, `crtime`, `mtime`
+//! and `ctime`, file rows without one add a tag.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Write},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    database::{from_nanos, to_nanos, TagAttr},
+    error::{Error, Result},
+    Tag, TagsFsDb,
+};
+
+/// Version of the export format, incremented on incompatible changes
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format {s:?}, expected json or csv")),
+        }
+    }
+}
+
+/// How an import treats what is already in the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Add to the database, tag attributes and settings in the import win
+    Merge,
+    /// Remove all tags and settings before importing
+    Replace,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Export {
+    pub version: u32,
+    pub files: Vec<FileEntry>,
+    pub tags: Vec<TagEntry>,
+    pub settings: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub name: String,
+    pub tags: BTreeSet<Tag>,
+    /// Where the file was before a tree import moved or linked it into `source`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TagEntry {
+    pub name: Tag,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crtime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ctime: Option<i64>,
+}
+
+/// A line of the CSV format
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Row {
+    kind: String,
+    name: String,
+    field: String,
+    value: String,
+}
+
+impl Export {
+    pub fn from_db(db: &TagsFsDb) -> Result<Self> {
+        let mut files = BTreeMap::new();
+        for (name, tag) in db.all_file_tags()? {
+            file_entry(&mut files, &name).tags.insert(tag);
+        }
+        for (name, origin) in db.origins()? {
+            file_entry(&mut files, &name).origin = Some(origin);
+        }
+        let mut tags = Vec::new();
+        for (tag, _) in db.tag_counts()? {
+            let attr = db.tag_attr(&tag)?;
+            tags.push(TagEntry {
+                name: tag,
+                mode: attr.mode,
+                uid: attr.uid,
+                gid: attr.gid,
+                crtime: attr.crtime.map(to_nanos),
+                mtime: attr.mtime.map(to_nanos),
+                ctime: attr.ctime.map(to_nanos),
+            });
+        }
+        Ok(Export {
+            version: VERSION,
+            files: files.into_values().collect(),
+            tags,
+            settings: db.settings()?.into_iter().collect(),
+        })
+    }
+
+    /// Write everything into `db`, running this twice leaves `db` the same as running it once
+    pub fn apply(&self, db: &TagsFsDb, mode: Mode) -> Result<()> {
+        self.validate()?;
+        db.transaction(|db| {
+            if mode == Mode::Replace {
+                db.clear()?;
+            }
+            for tag in &self.tags {
+                if db.tag_id(&tag.name).is_err() {
+                    db.create_tag(&tag.name)?;
+                }
+            }
+            for file in &self.files {
+                let current = db.file_tags(&file.name)?;
+                db.add_tags_to_file(file.tags.difference(&current), &file.name)?;
+                if let Some(origin) = &file.origin {
+                    db.set_origin(&file.name, origin)?;
+                }
+            }
+            // tagging the files bumped the times of the tags, so these go last
+            for tag in &self.tags {
+                db.write_tag_attr(
+                    &tag.name,
+                    &TagAttr {
+                        mode: tag.mode,
+                        uid: tag.uid,
+                        gid: tag.gid,
+                        crtime: tag.crtime.map(from_nanos),
+                        mtime: tag.mtime.map(from_nanos),
+                        ctime: tag.ctime.map(from_nanos),
+                    },
+                )?;
+            }
+            for (key, value) in &self.settings {
+                db.set_setting(key, value)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.version != VERSION {
+            return Err(Error::InvalidExport(format!(
+                "unsupported version {}, expected {VERSION}",
+                self.version
+            )));
+        }
+        let tags = self.tags.iter().map(|tag| &tag.name);
+        for tag in tags.chain(self.files.iter().flat_map(|file| &file.tags)) {
+            if tag.is_empty() || tag.contains('/') {
+                return Err(Error::InvalidExport(format!("invalid tag {tag:?}")));
+            }
+        }
+        for file in &self.files {
+            if file.name.is_empty() || file.name.contains('/') {
+                return Err(Error::InvalidExport(format!(
+                    "invalid file {:?}",
+                    file.name
+                )));
+            }
+        }
+        for (key, value) in &self.settings {
+            Config::default().set(key, value)?;
+        }
+        Ok(())
+    }
+
+    pub fn write<W: Write>(&self, writer: W, format: Format) -> Result<()> {
+        match format {
+            Format::Json => serde_json::to_writer_pretty(writer, self)?,
+            Format::Csv => {
+                let mut writer = csv::Writer::from_writer(writer);
+                for row in self.rows() {
+                    writer.serialize(row)?;
+                }
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(reader: R, format: Format) -> Result<Self> {
+        match format {
+            Format::Json => Ok(serde_json::from_reader(reader)?),
+            Format::Csv => {
+                let mut reader = csv::Reader::from_reader(reader);
+                let rows = reader
+                    .deserialize()
+                    .collect::<std::result::Result<Vec<Row>, _>>()?;
+                Self::from_rows(rows)
+            }
+        }
+    }
+
+    fn rows(&self) -> Vec<Row> {
+        let row = |kind: &str, name: &str, field: &str, value: String| Row {
+            kind: kind.into(),
+            name: name.into(),
+            field: field.into(),
+            value,
+        };
+        let mut rows = vec![row("version", "", "", self.version.to_string())];
+        for tag in &self.tags {
+            rows.push(row("tag", &tag.name, "", String::new()));
+            let attrs = [
+                ("mode", tag.mode.map(i64::from)),
+                ("uid", tag.uid.map(i64::from)),
+                ("gid", tag.gid.map(i64::from)),
+                ("crtime", tag.crtime),
+                ("mtime", tag.mtime),
+                ("ctime", tag.ctime),
+            ];
+            for (field, value) in attrs {
+                if let Some(value) = value {
+                    rows.push(row("tag", &tag.name, field, value.to_string()));
+                }
+            }
+        }
+        for file in &self.files {
+            for tag in &file.tags {
+                rows.push(row("file", &file.name, "", tag.clone()));
+            }
+            if let Some(origin) = &file.origin {
+                rows.push(row("file", &file.name, "origin", origin.clone()));
+            }
+        }
+        for (key, value) in &self.settings {
+            rows.push(row("setting", key, "", value.clone()));
+        }
+        rows
+    }
+
+    fn from_rows(rows: Vec<Row>) -> Result<Self> {
+        let mut export = Export::default();
+        let mut tags: BTreeMap<Tag, TagEntry> = BTreeMap::new();
+        let mut files = BTreeMap::new();
+        for row in rows {
+            let invalid = || Error::InvalidExport(format!("invalid row {row:?}"));
+            match (row.kind.as_str(), row.field.as_str()) {
+                ("version", "") => export.version = row.value.parse().map_err(|_| invalid())?,
+                ("tag", field) => {
+                    let tag = tags.entry(row.name.clone()).or_insert_with(|| TagEntry {
+                        name: row.name.clone(),
+                        ..TagEntry::default()
+                    });
+                    match field {
+                        "" => {}
+                        "mode" => tag.mode = Some(row.value.parse().map_err(|_| invalid())?),
+                        "uid" => tag.uid = Some(row.value.parse().map_err(|_| invalid())?),
+                        "gid" => tag.gid = Some(row.value.parse().map_err(|_| invalid())?),
+                        "crtime" => tag.crtime = Some(row.value.parse().map_err(|_| invalid())?),
+                        "mtime" => tag.mtime = Some(row.value.parse().map_err(|_| invalid())?),
+                        "ctime" => tag.ctime = Some(row.value.parse().map_err(|_| invalid())?),
+                        _ => return Err(invalid()),
+                    }
+                }
+                ("file", field) => {
+                    let file = file_entry(&mut files, &row.name);
+                    match field {
+                        "" => {
+                            file.tags.insert(row.value.clone());
+                        }
+                        "origin" => file.origin = Some(row.value.clone()),
+                        _ => return Err(invalid()),
+                    }
+                }
+                ("setting", "") => {
+                    export.settings.insert(row.name.clone(), row.value.clone());
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        export.tags = tags.into_values().collect();
+        export.files = files.into_values().collect();
+        Ok(export)
+    }
+}
+
+/// The entry of the file `name` in `files`, added if it's missing
+fn file_entry<'a>(files: &'a mut BTreeMap<String, FileEntry>, name: &str) -> &'a mut FileEntry {
+    files.entry(name.to_string()).or_insert_with(|| FileEntry {
+        name: name.to_string(),
+        ..FileEntry::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(export: &Export) -> String {
+        let mut json = Vec::new();
+        export.write(&mut json, Format::Json).unwrap();
+        String::from_utf8(json).unwrap()
+    }
+
+    fn library() -> TagsFsDb {
+        let db = TagsFsDb::in_memory();
+        db.add_tags_to_file(["italy", "2019"], "rome.jpg").unwrap();
+        db.add_tags_to_file(["italy"], "venice, at night.jpg")
+            .unwrap();
+        db.create_tag("empty").unwrap();
+        db.set_tag_attr("italy", Some(0o750), Some(1000), None, None)
+            .unwrap();
+        db.set_setting("source", "/home/me/library").unwrap();
+        db.set_origin("rome.jpg", "/photos/2019/Italy/rome.jpg")
+            .unwrap();
+        db.set_origin("notes.txt", "/old, files/notes.txt").unwrap();
+        db
+    }
+
+    #[test]
+    fn round_trips() {
+        let export = Export::from_db(&library()).unwrap();
+        for format in [Format::Json, Format::Csv] {
+            let mut written = Vec::new();
+            export.write(&mut written, format).unwrap();
+            let db = TagsFsDb::in_memory();
+            Export::read(written.as_slice(), format)
+                .unwrap()
+                .apply(&db, Mode::Replace)
+                .unwrap();
+            assert_eq!(
+                json(&Export::from_db(&db).unwrap()),
+                json(&export),
+                "{format:?}"
+            );
+            assert_eq!(
+                db.origin("rome.jpg").unwrap().as_deref(),
+                Some("/photos/2019/Italy/rome.jpg")
+            );
+            // untagged files keep their origin as well
+            assert_eq!(
+                db.origin("notes.txt").unwrap().as_deref(),
+                Some("/old, files/notes.txt")
+            );
+        }
+    }
+
+    #[test]
+    fn importing_twice_changes_nothing() {
+        let export = Export::from_db(&library()).unwrap();
+        let db = TagsFsDb::in_memory();
+        export.apply(&db, Mode::Merge).unwrap();
+        export.apply(&db, Mode::Merge).unwrap();
+        assert_eq!(json(&Export::from_db(&db).unwrap()), json(&export));
+    }
+
+    #[test]
+    fn merging_keeps_attributes_the_import_lacks() {
+        let db = library();
+        let export = Export {
+            version: VERSION,
+            tags: vec![TagEntry {
+                name: "italy".into(),
+                gid: Some(100),
+                ..TagEntry::default()
+            }],
+            ..Export::default()
+        };
+        export.apply(&db, Mode::Merge).unwrap();
+        let attr = db.tag_attr("italy").unwrap();
+        assert_eq!(
+            (attr.mode, attr.uid, attr.gid),
+            (Some(0o750), Some(1000), Some(100))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_tags() {
+        let export = Export {
+            version: VERSION,
+            files: vec![FileEntry {
+                name: "rome.jpg".into(),
+                tags: BTreeSet::from(["a/b".to_string()]),
+                origin: None,
+            }],
+            ..Export::default()
+        };
+        assert!(export.apply(&TagsFsDb::in_memory(), Mode::Merge).is_err());
+    }
+}
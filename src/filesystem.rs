@@ -1,14 +1,15 @@
 #![allow(unused_imports, unused_variables, dead_code)]
 use std::{
     borrow::Cow,
-    collections::{BTreeSet, HashSet},
+    collections::{BTreeSet, HashMap, HashSet},
     ffi::{CStr, CString, OsStr, OsString},
     fs::{self, File, FileType},
     hash::Hash,
     io::{Read, Seek, SeekFrom, Write},
     mem,
-    os::unix::prelude::{MetadataExt, OsStrExt, PermissionsExt},
+    os::unix::prelude::{AsRawFd, MetadataExt, OsStrExt, PermissionsExt},
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
     time::{Duration, SystemTime},
 };
 
@@ -20,12 +21,79 @@ use libc::{c_int, EINVAL, ENODATA, ENODEV, ENOENT, ENOSYS, EPERM};
 use log::{debug, info, trace, warn};
 use rand::thread_rng;
 
+use crate::config::Rules;
+use crate::database::TagAttr;
 use crate::error::{Error, Result};
-use crate::TagsFsDb;
+use crate::lock::{Lock, LockManager};
+use crate::xattr;
+use crate::{Tag, TagsFsDb};
+
+/// Longest path component the kernel passes to a FUSE file system
+const NAME_MAX: u32 = 255;
+
+/// How long the kernel may cache entries and attributes before asking again
+///
+/// Kept short since the command line tools change tags behind the kernel's back.
+const TTL: Duration = Duration::from_secs(1);
+
+/// Directory in `source` deleted files are moved to
+const TRASH_DIR: &str = ".tagsfs-trash";
+/// Virtual directory in the root of the mount which shows the trash
+const TRASH_NAME: &str = ".trash";
+
+/// Names of the temporary files common editors create next to the file they save
+///
+/// `4913` is the file vim creates to check whether it may write to a directory.
+pub const DEFAULT_IGNORE: &[&str] = &[".*.sw?", "*~", ".#*", "#*#", "4913"];
+
+/// Extended attribute with the tags of a file, separated by commas or newlines
+const TAGS_XATTR: &str = "user.tags";
+/// Namespace of extended attributes tagsfs provides itself instead of the backing file
+const TAGSFS_XATTR_PREFIX: &str = "user.tagsfs.";
+/// Read-only extended attribute of imported files with the path they were imported from
+const ORIGIN_XATTR: &str = "user.tagsfs.origin";
+/// Read-only extended attribute of tag directories with the tags they select for
+const QUERY_XATTR: &str = "user.tagsfs.query";
+/// Read-only extended attribute of tag directories with the number of files they contain
+const COUNT_XATTR: &str = "user.tagsfs.count";
 
 pub struct TagsFs {
     pub db: TagsFsDb,
     pub source: PathBuf,
+    /// Mirror tag changes into the freedesktop `user.xdg.tags` attribute of the backing files
+    pub xdg_sync: bool,
+    /// Let `rmdir` of a non-empty tag directory remove its tag from the files in it
+    pub rmdir_untags: bool,
+    /// How long deleted files are kept in the trash, `None` keeps them until deleted from there
+    pub trash_retention: Option<Duration>,
+    /// Patterns (`*` and `?` wildcards) of file names which are never tagged
+    ///
+    /// Matching files are visible in every tag directory and the untagged ones `create` made are
+    /// deleted for good by `unlink`, so editors can use them for their swap and backup files
+    /// without touching `file_tags`.
+    pub ignore: Vec<String>,
+    /// Untagged files `create` made for a name matching `ignore`
+    temp_files: HashSet<OsString>,
+    /// Refuse every change with `EROFS`, set when `db` was opened read-only
+    pub read_only: bool,
+    locks: LockManager,
+    /// Entries of the open directories by file handle, so a listing is read only once
+    listings: HashMap<u64, Vec<(OsString, FileAttr)>>,
+    next_fh: u64,
+    /// Rules to switch to with the next request, see [`TagsFs::rules_handle`]
+    pending_rules: Arc<Mutex<Option<Rules>>>,
+    destroy_hook: Option<Box<dyn FnOnce() + Send>>,
+}
+
+/// Replaces the rules of a mounted [`TagsFs`]
+#[derive(Clone)]
+pub struct RulesHandle(Arc<Mutex<Option<Rules>>>);
+
+impl RulesHandle {
+    /// Let the file system use `rules` from its next request on
+    pub fn replace(&self, rules: Rules) {
+        *self.0.lock().unwrap() = Some(rules);
+    }
 }
 
 impl TagsFs {
@@ -35,37 +103,870 @@ impl TagsFs {
             Some(source) => source,
             None => db.source()?,
         };
-        Ok(Self { db, source })
+        Ok(Self::from_db(db, source))
+    }
+
+    /// Settings for mounting from within another program, see [`crate::mount`]
+    pub fn builder() -> crate::mount::Builder {
+        crate::mount::Builder::default()
+    }
+
+    /// Serve the files in `source` with the tags in the already opened `db`
+    pub fn from_db(db: TagsFsDb, source: PathBuf) -> Self {
+        let read_only = db.is_read_only();
+        Self {
+            db,
+            source,
+            xdg_sync: false,
+            rmdir_untags: false,
+            trash_retention: None,
+            ignore: DEFAULT_IGNORE.iter().map(|p| p.to_string()).collect(),
+            temp_files: HashSet::new(),
+            read_only,
+            locks: LockManager::default(),
+            listings: HashMap::new(),
+            next_fh: 1,
+            pending_rules: Arc::default(),
+            destroy_hook: None,
+        }
+    }
+
+    /// A handle to change the rules after the file system was moved into its mount
+    pub fn rules_handle(&self) -> RulesHandle {
+        RulesHandle(self.pending_rules.clone())
+    }
+
+    /// Run `hook` once the file system is unmounted, however that happens
+    pub fn on_destroy(&mut self, hook: impl FnOnce() + Send + 'static) {
+        self.destroy_hook = Some(Box::new(hook));
+    }
+
+    fn reload_rules(&mut self) {
+        let rules = self.pending_rules.lock().unwrap().take();
+        if let Some(rules) = rules {
+            info!("switching to new rules {rules:?}");
+            rules.apply(self);
+        }
+    }
+
+    /// Whether `name` matches one of the `ignore` patterns
+    fn is_ignored(&self, name: &OsStr) -> bool {
+        let name = name.to_string_lossy();
+        self.ignore
+            .iter()
+            .any(|pattern| wildcard_match(pattern.as_bytes(), name.as_bytes()))
     }
 
     fn find_file<S: AsRef<Path>>(&self, name: S) -> Result<PathBuf> {
         Ok(self.source.join(name).canonicalize()?)
     }
 
-    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr) -> Result<FileAttr> {
+    /// Add the tags desktop tools stored in `user.xdg.tags` of the files in `source`
+    ///
+    /// Returns the number of files which got new tags.
+    pub fn import_xdg_tags(&self) -> Result<usize> {
+        self.db.transaction(|db| {
+            let mut changed = 0;
+            for file in fs::read_dir(&self.source)? {
+                let file = file?;
+                if !file.path().is_file() {
+                    continue;
+                }
+                let name = file.file_name().to_string_lossy().to_string();
+                if let Some(tags) = xattr::read_xdg_tags(&file.path())? {
+                    let current = db.file_tags(&name)?;
+                    let new = tags.difference(&current).collect::<Vec<_>>();
+                    if !new.is_empty() {
+                        db.add_tags_to_file(new, &name)?;
+                        changed += 1;
+                    }
+                }
+            }
+            Ok(changed)
+        })
+    }
+
+    /// Write the tags of `file` to its `user.xdg.tags` if syncing with desktop tools is enabled
+    fn sync_xdg_tags(&self, file: &str) {
+        if !self.xdg_sync {
+            return;
+        }
+        let synced = self
+            .db
+            .file_tags(file)
+            .and_then(|tags| xattr::write_xdg_tags(&self.find_file(file)?, &tags));
+        if let Err(err) = synced {
+            warn!("couldn't sync {} of {file:?}: {err:?}", xattr::XDG_TAGS);
+        }
+    }
+
+    /// Attributes of `ino`, tag directories show the attributes of their tags
+    fn attr(&self, ino: u64) -> Result<FileAttr> {
+        match self.db.entry(ino)? {
+            Entry::File(name) => Ok(file_attr_of_file(ino, self.find_file(name)?)),
+            Entry::Tags(tags) => self.tags_attr(ino, &tags),
+            Entry::Trash => Ok(self.trash_attr(ino)),
+        }
+    }
+
+    fn tags_attr(&self, ino: u64, tags: &BTreeSet<Tag>) -> Result<FileAttr> {
+        self.tag_dir_attr(file_attr_of_file(ino, &self.source), tags)
+    }
+
+    /// Attributes of the tag directory `tags` starting from `attr`, the attributes of `source`
+    ///
+    /// A directory of several tags shows the permissions all of its tags grant, the owner of the
+    /// first tag and the newest times of any of them. The root directory is `source` itself.
+    fn tag_dir_attr(&self, attr: FileAttr, tags: &BTreeSet<Tag>) -> Result<FileAttr> {
+        if tags.is_empty() {
+            return Ok(attr);
+        }
+        let tag_attrs = tags
+            .iter()
+            .map(|tag| self.db.tag_attr(tag))
+            .collect::<Result<Vec<_>>>()?;
+        let sub_tags = self.db.sub_tags(tags)?.len() as u64;
+        let files = self.db.files_with_tags(tags)?.len() as u64;
+        Ok(merge_tag_attrs(attr, &tag_attrs, files, sub_tags))
+    }
+
+    /// Apply `chmod`, `chown` and `touch` on a tag directory to all of its tags
+    fn set_tags_attr(
+        &self,
+        req: &Request<'_>,
+        ino: u64,
+        tags: &BTreeSet<Tag>,
+        (mode, uid, gid): (Option<u32>, Option<u32>, Option<u32>),
+        mtime: Option<TimeOrNow>,
+    ) -> Result<FileAttr> {
+        if tags.is_empty() {
+            return Err(Error::StdC(EINVAL));
+        }
+        let attr = self.tags_attr(ino, tags)?;
+        check_chmod_chown(req, &attr, mode, uid, gid)?;
+        let mtime = match mtime {
+            Some(TimeOrNow::SpecificTime(mtime)) if req.uid() == 0 || req.uid() == attr.uid => {
+                Some(mtime)
+            }
+            Some(TimeOrNow::SpecificTime(_)) => return Err(Error::StdC(libc::EPERM)),
+            Some(TimeOrNow::Now) => {
+                if req.uid() != attr.uid {
+                    check_permission(req, &attr, libc::W_OK)?;
+                }
+                Some(SystemTime::now())
+            }
+            None => None,
+        };
+        for tag in tags {
+            self.db.set_tag_attr(tag, mode, uid, gid, mtime)?;
+        }
+        self.tags_attr(ino, tags)
+    }
+
+    /// Fail with `EROFS` on a read-only mount
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::StdC(libc::EROFS));
+        }
+        Ok(())
+    }
+
+    /// Check that the caller of `req` may access `ino` as requested by `mask`
+    fn check_access(&self, req: &Request<'_>, ino: u64, mask: i32) -> Result<()> {
+        check_permission(req, &self.attr(ino)?, mask)
+    }
+
+    /// Open the backing file of `ino` in `source`, tag directories have none
+    fn backing_file(&self, ino: u64, options: &fs::OpenOptions) -> Result<File> {
+        match self.db.entry(ino)? {
+            Entry::File(name) => Ok(options.open(self.find_file(name)?)?),
+            Entry::Tags(_) | Entry::Trash => Err(Error::StdC(libc::EISDIR)),
+        }
+    }
+
+    /// All entries of the tag directory `tags` with their attributes
+    ///
+    /// Membership and inodes are fetched with one query each and every file is only stat'ed once
+    /// while reading `source`, so listing a large directory doesn't cost a lookup per entry.
+    fn dir_entries(&self, tags: &BTreeSet<Tag>) -> Result<Vec<(OsString, FileAttr)>> {
+        let tagged = if tags.is_empty() {
+            None
+        } else {
+            Some(self.db.files_with_tags(tags)?)
+        };
+        let mut inodes = self.db.file_inodes()?;
+        let mut entries = Vec::new();
+        for file in fs::read_dir(&self.source)? {
+            let file = file?;
+            let name = file.file_name();
+            if let Some(tagged) = &tagged {
+                if !tagged.contains(name.to_string_lossy().as_ref()) && !self.is_ignored(&name) {
+                    continue;
+                }
+            }
+            // files imported as symlinks show up as the file they point to
+            let metadata = match fs::metadata(file.path()) {
+                Ok(metadata) if metadata.is_file() => metadata,
+                _ => continue,
+            };
+            let ino = match inodes.remove(&name) {
+                Some(ino) => ino,
+                None => self.db.create_inode(&Entry::File(name.clone()))?,
+            };
+            entries.push((name, file_attr_of_metadata(ino, &metadata)));
+        }
+        // tag directories fall back to the attributes of `source`, only stat it once
+        let source_metadata = fs::metadata(&self.source)?;
+        // the attributes of all sub directories come from a fixed number of queries
+        let tag_attrs = self.db.tag_attrs()?;
+        let counts = self.db.sub_tag_counts(tags)?;
+        let mut tag_inodes = self.db.tag_inodes()?;
+        let sub_tags = self.db.sub_tags(tags)?;
+        // all tags but the ones of the directory itself
+        let sub_sub_tags = sub_tags.len().saturating_sub(1) as u64;
+        for tag in sub_tags {
+            let mut sub_tags = tags.clone();
+            sub_tags.insert(tag.clone());
+            let entry = Entry::Tags(sub_tags.clone());
+            let ino = match tag_inodes.remove(entry.discrimimant_data().1.as_ref()) {
+                Some(ino) => ino,
+                None => self.db.create_inode(&entry)?,
+            };
+            let attrs: Vec<_> = sub_tags
+                .iter()
+                .map(|tag| tag_attrs.get(tag).copied().unwrap_or_default())
+                .collect();
+            let attr = merge_tag_attrs(
+                file_attr_of_metadata(ino, &source_metadata),
+                &attrs,
+                counts.get(&tag).copied().unwrap_or(0),
+                sub_sub_tags,
+            );
+            entries.push((tag.into(), attr));
+        }
+        if tags.is_empty() {
+            let ino = self.db.inode_or_create(&Entry::Trash)?;
+            entries.push((TRASH_NAME.into(), self.trash_attr(ino)));
+        }
+        Ok(entries)
+    }
+
+    /// Entries of the directory `ino` with their attributes
+    fn listing(&self, ino: u64) -> Result<Vec<(OsString, FileAttr)>> {
+        match self.db.entry(ino)? {
+            Entry::Tags(tags) => self.dir_entries(&tags),
+            Entry::Trash => self.trash_entries(),
+            Entry::File(_) => Err(Error::StdC(libc::ENOTDIR)),
+        }
+    }
+
+    /// The listing read by `opendir` for `fh`, or a fresh one if there is none
+    fn open_listing(&mut self, ino: u64, fh: u64) -> Result<&[(OsString, FileAttr)]> {
+        if !self.listings.contains_key(&fh) {
+            let listing = self.listing(ino)?;
+            self.listings.insert(fh, listing);
+        }
+        Ok(&self.listings[&fh])
+    }
+
+    fn trash_path(&self) -> PathBuf {
+        self.source.join(TRASH_DIR)
+    }
+
+    /// The trash directory shows the attributes of its backing directory once it exists
+    fn trash_attr(&self, ino: u64) -> FileAttr {
+        let trash = self.trash_path();
+        file_attr_of_file(ino, if trash.is_dir() { &trash } else { &self.source })
+    }
+
+    fn trash_entries(&self) -> Result<Vec<(OsString, FileAttr)>> {
+        let mut entries = Vec::new();
+        if !self.trash_path().is_dir() {
+            return Ok(entries);
+        }
+        for file in fs::read_dir(self.trash_path())? {
+            let file = file?;
+            let metadata = match fs::metadata(file.path()) {
+                Ok(metadata) if metadata.is_file() => metadata,
+                _ => continue,
+            };
+            let name = file.file_name();
+            let ino = self
+                .db
+                .inode_or_create(&Entry::File(trash_key(&name).into()))?;
+            entries.push((name, file_attr_of_metadata(ino, &metadata)));
+        }
+        Ok(entries)
+    }
+
+    /// Delete `name` from the root: the file is moved to the trash and keeps its tags
+    fn trash(&self, name: &OsStr) -> Result<()> {
+        let path = self.source.join(name);
+        if !path.is_file() {
+            return Err(Error::StdC(ENOENT));
+        }
+        fs::create_dir_all(self.trash_path())?;
+        // files deleted earlier under the same name stay in the trash as well
+        let mut trashed = name.to_os_string();
+        let mut n = 1;
+        while self.trash_path().join(&trashed).symlink_metadata().is_ok() {
+            trashed = format!("{}.{n}", name.to_string_lossy()).into();
+            n += 1;
+        }
+        fs::rename(&path, self.trash_path().join(&trashed))?;
+        let file = name.to_string_lossy();
+        let key = trash_key(&trashed);
+        self.db.transaction(|db| {
+            db.rename_file(&file, &key)?;
+            db.add_trashed(&key, &file)
+        })?;
+        if let Some(retention) = self.trash_retention {
+            self.purge_trash(retention)?;
+        }
+        Ok(())
+    }
+
+    /// Move the trashed file `name` back into `source` as `newname`, adding the tags `newtags`
+    fn restore(&self, name: &OsStr, newtags: &BTreeSet<Tag>, newname: &OsStr) -> Result<()> {
+        let key = trash_key(name);
+        if !self.db.is_trashed(&key)? {
+            return Err(Error::StdC(ENOENT));
+        }
+        let newfile = newname.to_string_lossy().to_string();
+        let target = self.source.join(newname);
+        if target.symlink_metadata().is_ok() || self.db.tag_id(&newfile).is_ok() {
+            return Err(Error::StdC(libc::EEXIST));
+        }
+        fs::rename(self.source.join(&key), &target)?;
+        self.db.transaction(|db| {
+            db.remove_trashed(&key)?;
+            db.rename_file(&key, &newfile)?;
+            let current = db.file_tags(&newfile)?;
+            db.add_tags_to_file(newtags.difference(&current), &newfile)
+        })?;
+        self.sync_xdg_tags(&newfile);
+        Ok(())
+    }
+
+    /// Permanently delete the files which are in the trash for longer than `retention`
+    ///
+    /// Returns the number of deleted files.
+    pub fn purge_trash(&self, retention: Duration) -> Result<usize> {
+        let expired = match SystemTime::now().checked_sub(retention) {
+            Some(time) => self.db.trashed_before(time)?,
+            // nothing was deleted that long ago
+            None => return Ok(0),
+        };
+        for key in &expired {
+            self.delete_trashed(key)?;
+        }
+        Ok(expired.len())
+    }
+
+    fn delete_trashed(&self, key: &str) -> Result<()> {
+        match fs::remove_file(self.source.join(key)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        self.db.transaction(|db| {
+            db.forget_file(key)?;
+            db.remove_trashed(key)
+        })
+    }
+
+    /// Remove `name` from the tag directory `parent`
+    ///
+    /// In a tag directory this removes the tags of `parent` from the file, in the root the file
+    /// is moved to the trash and deleting it from the trash deletes it for good. Temporary files
+    /// of editors are deleted right away, see `ignore`.
+    fn unlink(&mut self, parent: u64, name: &OsStr) -> Result<()> {
+        if let Entry::Trash = self.db.entry(parent)? {
+            let key = trash_key(name);
+            if !self.db.is_trashed(&key)? {
+                return Err(Error::StdC(ENOENT));
+            }
+            return self.delete_trashed(&key);
+        }
+        let tags = self.dir_tags(parent)?;
+        if self.temp_files.contains(name)
+            && self.source.join(name).is_file()
+            && self.db.file_tags(name.to_string_lossy())?.is_empty()
+        {
+            fs::remove_file(self.source.join(name))?;
+            self.db.forget_file(&name.to_string_lossy())?;
+            self.temp_files.remove(name);
+            return Ok(());
+        }
+        if tags.is_empty() {
+            return self.trash(name);
+        }
+        let file = name.to_string_lossy();
+        self.db.remove_tags_from_file(&tags, &file)?;
+        self.sync_xdg_tags(&file);
+        Ok(())
+    }
+
+    /// Space of the file system `source` lives on and the number of files and tags in the mount
+    fn statfs(&self) -> Result<(libc::statvfs, u64)> {
+        let c_path =
+            unsafe { CString::from_vec_unchecked(self.source.as_os_str().as_bytes().to_vec()) };
+        let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+        let err = unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) };
+        if err != 0 {
+            return Err(Error::last_os_error());
+        }
+        let mut files = self.db.tag_count()?;
+        for file in fs::read_dir(&self.source)? {
+            if file?.path().is_file() {
+                files += 1;
+            }
+        }
+        Ok((stat, files))
+    }
+
+    fn getxattr(&self, req: &Request<'_>, ino: u64, name: &OsStr) -> Result<Vec<u8>> {
+        self.check_access(req, ino, libc::R_OK)?;
+        match (self.db.entry(ino)?, name.to_str()) {
+            (Entry::File(file), Some(TAGS_XATTR)) => {
+                let tags = self.db.file_tags(file.to_string_lossy())?;
+                if tags.is_empty() {
+                    return Err(Error::StdC(ENODATA));
+                }
+                Ok(tags.iter().join(",").into_bytes())
+            }
+            (Entry::File(file), Some(ORIGIN_XATTR)) => {
+                match self.db.origin(&file.to_string_lossy())? {
+                    Some(origin) => Ok(origin.into_bytes()),
+                    None => Err(Error::StdC(ENODATA)),
+                }
+            }
+            (Entry::File(file), _) if !is_reserved_xattr(name) => {
+                xattr::get(&self.find_file(file)?, name)
+            }
+            (Entry::Tags(tags), Some(QUERY_XATTR)) => Ok(tags.iter().join("/").into_bytes()),
+            (Entry::Tags(tags), Some(COUNT_XATTR)) => {
+                let count = if tags.is_empty() {
+                    self.dir_entries(&tags)?
+                        .iter()
+                        .filter(|(_, attr)| attr.kind == fuser::FileType::RegularFile)
+                        .count()
+                } else {
+                    self.db.files_with_tags(&tags)?.len()
+                };
+                Ok(count.to_string().into_bytes())
+            }
+            _ => Err(Error::StdC(ENODATA)),
+        }
+    }
+
+    /// Names of all extended attributes of `ino`, each terminated by a nul byte
+    fn listxattr(&self, ino: u64) -> Result<Vec<u8>> {
+        let mut names = Vec::new();
+        match self.db.entry(ino)? {
+            Entry::File(file) => {
+                if !self.db.file_tags(file.to_string_lossy())?.is_empty() {
+                    names.extend(TAGS_XATTR.bytes().chain(std::iter::once(0)));
+                }
+                if self.db.origin(&file.to_string_lossy())?.is_some() {
+                    names.extend(ORIGIN_XATTR.bytes().chain(std::iter::once(0)));
+                }
+                // attributes of the backing file which we shadow aren't reachable anyway
+                let backing = xattr::list(&self.find_file(file)?)?;
+                for name in backing.split(|b| *b == 0).filter(|name| !name.is_empty()) {
+                    if !is_reserved_xattr(OsStr::from_bytes(name)) {
+                        names.extend(name.iter().copied().chain(std::iter::once(0)));
+                    }
+                }
+            }
+            Entry::Tags(_) => {
+                for name in [QUERY_XATTR, COUNT_XATTR] {
+                    names.extend(name.bytes().chain(std::iter::once(0)));
+                }
+            }
+            Entry::Trash => {}
+        }
+        Ok(names)
+    }
+
+    /// Replace all tags of a file with the ones listed in `value`, other attributes are set on
+    /// the backing file
+    fn setxattr(
+        &self,
+        req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+    ) -> Result<()> {
+        let file = match (self.db.entry(ino)?, name.to_str()) {
+            (Entry::File(file), Some(TAGS_XATTR)) => file.to_string_lossy().to_string(),
+            (Entry::File(file), _) if !is_reserved_xattr(name) => {
+                self.check_access(req, ino, libc::W_OK)?;
+                let path = self.find_file(&file)?;
+                xattr::set(&path, name, value, flags)?;
+                // desktop tools changed the tags, take them over
+                if self.xdg_sync && name == xattr::XDG_TAGS {
+                    if let Some(tags) = xattr::read_xdg_tags(&path)? {
+                        let file = file.to_string_lossy();
+                        self.db.transaction(|db| db.set_file_tags(&tags, &file))?;
+                    }
+                }
+                return Ok(());
+            }
+            (Entry::Tags(_), Some(QUERY_XATTR | COUNT_XATTR)) => {
+                return Err(Error::StdC(libc::EPERM))
+            }
+            _ => return Err(Error::StdC(libc::ENOTSUP)),
+        };
+        self.check_access(req, ino, libc::W_OK)?;
+        let tags = parse_tags(value)?;
+        self.db.transaction(|db| {
+            let current = db.file_tags(&file)?;
+            if flags & libc::XATTR_CREATE != 0 && !current.is_empty() {
+                return Err(Error::StdC(libc::EEXIST));
+            }
+            if flags & libc::XATTR_REPLACE != 0 && current.is_empty() {
+                return Err(Error::StdC(ENODATA));
+            }
+            db.set_file_tags(&tags, &file)
+        })?;
+        self.sync_xdg_tags(&file);
+        Ok(())
+    }
+
+    fn removexattr(&self, req: &Request<'_>, ino: u64, name: &OsStr) -> Result<()> {
+        let file = match (self.db.entry(ino)?, name.to_str()) {
+            (Entry::File(file), Some(TAGS_XATTR)) => file.to_string_lossy().to_string(),
+            (Entry::File(file), _) if !is_reserved_xattr(name) => {
+                self.check_access(req, ino, libc::W_OK)?;
+                xattr::remove(&self.find_file(&file)?, name)?;
+                // desktop tools dropped all tags, drop them here as well
+                if self.xdg_sync && name == xattr::XDG_TAGS {
+                    let file = file.to_string_lossy();
+                    self.db
+                        .transaction(|db| db.set_file_tags(&BTreeSet::new(), &file))?;
+                }
+                return Ok(());
+            }
+            (Entry::Tags(_), Some(QUERY_XATTR | COUNT_XATTR)) => {
+                return Err(Error::StdC(libc::EPERM))
+            }
+            _ => return Err(Error::StdC(ENODATA)),
+        };
+        self.check_access(req, ino, libc::W_OK)?;
+        self.db.transaction(|db| {
+            let current = db.file_tags(&file)?;
+            if current.is_empty() {
+                return Err(Error::StdC(ENODATA));
+            }
+            db.remove_tags_from_file(&current, &file)
+        })?;
+        self.sync_xdg_tags(&file);
+        Ok(())
+    }
+
+    /// Tags of the directory `ino`
+    fn dir_tags(&self, ino: u64) -> Result<BTreeSet<Tag>> {
+        match self.db.entry(ino)? {
+            Entry::Tags(tags) => Ok(tags),
+            Entry::File(_) => Err(Error::StdC(libc::ENOTDIR)),
+            // nothing can be created in the trash, files only get there by being deleted
+            Entry::Trash => Err(Error::StdC(libc::EPERM)),
+        }
+    }
+
+    /// Move the file `name` from the tag directory `parent` to `newparent` as `newname`
+    ///
+    /// Tags of `parent` which `newparent` doesn't have are removed from the file and the other
+    /// way around. A different `newname` renames the backing file in `source`, replacing or
+    /// exchanging a file visible in `newparent` as requested by `flags`.
+    fn rename(
+        &self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<()> {
+        let newtags = self.dir_tags(newparent)?;
+        if let Entry::Trash = self.db.entry(parent)? {
+            return self.restore(name, &newtags, newname);
+        }
+        let tags = self.dir_tags(parent)?;
+        let exchange = flags & libc::RENAME_EXCHANGE != 0;
+        let noreplace = flags & libc::RENAME_NOREPLACE != 0;
+        if exchange && noreplace {
+            return Err(Error::StdC(EINVAL));
+        }
+        let file = name.to_string_lossy().to_string();
+        let newfile = newname.to_string_lossy().to_string();
+        if !self.source.join(name).is_file() && self.db.tag_id(&file).is_ok() {
+            return self.rename_tag(&file, &tags, &newtags, &newfile, flags);
+        }
+        let visible = self.is_ignored(name) || tags.is_subset(&self.db.file_tags(&file)?);
+        if !self.source.join(name).is_file() || !visible {
+            return Err(Error::StdC(ENOENT));
+        }
+
+        let renamed = name != newname;
+        let mut replaced = false;
+        if renamed {
+            // a file can't hide a tag directory of the same name
+            if self.db.tag_id(&newfile).is_ok() {
+                return Err(Error::StdC(libc::EEXIST));
+            }
+            let target = self.source.join(newname);
+            if target.symlink_metadata().is_ok() {
+                // only files visible in `newparent` may be replaced or exchanged
+                if noreplace || !newtags.is_subset(&self.db.file_tags(&newfile)?) {
+                    return Err(Error::StdC(libc::EEXIST));
+                }
+                replaced = !exchange;
+            } else if exchange {
+                return Err(Error::StdC(ENOENT));
+            }
+            let c_from = xattr::c_string(self.source.join(name).as_os_str());
+            let c_to = xattr::c_string(target.as_os_str());
+            let err = unsafe {
+                libc::renameat2(
+                    libc::AT_FDCWD,
+                    c_from.as_ptr(),
+                    libc::AT_FDCWD,
+                    c_to.as_ptr(),
+                    flags & (libc::RENAME_EXCHANGE | libc::RENAME_NOREPLACE),
+                )
+            };
+            if err != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+
+        self.db.transaction(|db| {
+            if renamed {
+                if exchange {
+                    db.exchange_files(&file, &newfile)?;
+                } else {
+                    if replaced {
+                        // editors save by writing a temporary file and renaming it over the
+                        // original, the new content has to keep the tags of the original
+                        db.replace_file(&file, &newfile)?;
+                    } else {
+                        db.rename_file(&file, &newfile)?;
+                    }
+                }
+            }
+            retag(db, &newfile, &tags, &newtags)?;
+            if renamed && exchange {
+                // the file which was at `newname` went the opposite way
+                retag(db, &file, &newtags, &tags)?;
+            }
+            Ok(())
+        })?;
+        self.sync_xdg_tags(&newfile);
+        if renamed && exchange {
+            self.sync_xdg_tags(&file);
+        }
+        Ok(())
+    }
+
+    /// Rename the tag `tag` to `newtag`, merging it into `newtag` if that already exists
+    ///
+    /// Tags are renamed everywhere at once, so moving a tag directory below other tags fails
+    /// with `EINVAL` instead of pretending it moved. `EXDEV` would make `mv` copy the files.
+    fn rename_tag(
+        &self,
+        tag: &str,
+        tags: &BTreeSet<Tag>,
+        newtags: &BTreeSet<Tag>,
+        newtag: &str,
+        flags: u32,
+    ) -> Result<()> {
+        if tags != newtags {
+            return Err(Error::StdC(EINVAL));
+        }
+        if tag == newtag {
+            return Ok(());
+        }
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            return Err(Error::StdC(EINVAL));
+        }
+        // the directory would end up inside of itself or behind a file of the same name
+        if newtags.contains(tag) || newtags.contains(newtag) || self.source.join(newtag).exists() {
+            return Err(Error::StdC(EINVAL));
+        }
+        if flags & libc::RENAME_NOREPLACE != 0 && self.db.tag_id(newtag).is_ok() {
+            return Err(Error::StdC(libc::EEXIST));
+        }
+        let files = self
+            .db
+            .files_with_tags(&BTreeSet::from([tag.to_string()]))?;
+        self.db.transaction(|db| db.rename_tag(tag, newtag))?;
+        for file in files {
+            self.sync_xdg_tags(&file);
+        }
+        Ok(())
+    }
+
+    /// Remove the tag directory `name` from `parent`
+    ///
+    /// This only ever affects the files below `parent`: if there are any the tag is removed from
+    /// them when `rmdir_untags` is set, otherwise this fails with `ENOTEMPTY`. The tag itself is
+    /// deleted once no file carries it anymore. Without `rmdir_untags` a tag which files outside
+    /// of `parent` still use fails with `EBUSY`, since its directory wouldn't go away. Deleting a
+    /// tag from the whole library is left to [`TagsFsDb::delete_tags`].
+    fn rmdir(&self, parent: u64, name: &OsStr) -> Result<()> {
+        let mut tags = self.dir_tags(parent)?;
+        if tags.is_empty() && name == TRASH_NAME {
+            return Err(Error::StdC(libc::EPERM));
+        }
+        let tag = name.to_string_lossy().to_string();
+        if self.source.join(name).is_file() {
+            return Err(Error::StdC(libc::ENOTDIR));
+        }
+        if tags.contains(&tag) || self.db.tag_id(&tag).is_err() {
+            return Err(Error::StdC(ENOENT));
+        }
+        tags.insert(tag.clone());
+        let files = self.db.files_with_tags(&tags)?;
+        if !files.is_empty() && !self.rmdir_untags {
+            return Err(Error::StdC(libc::ENOTEMPTY));
+        }
+        let tag = BTreeSet::from([tag]);
+        let unused = self.db.files_with_tags(&tag)?.is_subset(&files);
+        if !unused && !self.rmdir_untags {
+            return Err(Error::StdC(libc::EBUSY));
+        }
+        self.db.transaction(|db| {
+            for file in &files {
+                db.remove_tags_from_file(&tag, file)?;
+            }
+            if unused {
+                db.delete_tags(&tag)?;
+            }
+            Ok(())
+        })?;
+        for file in files {
+            self.sync_xdg_tags(&file);
+        }
+        Ok(())
+    }
+
+    fn lseek(&self, ino: u64, offset: i64, whence: i32) -> Result<i64> {
+        let file = self.backing_file(ino, File::options().read(true))?;
+        // SEEK_DATA and SEEK_HOLE are answered by the backing file system so sparse files stay
+        // sparse when copied through the mount
+        let offset = unsafe { libc::lseek(file.as_raw_fd(), offset, whence) };
+        if offset < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(offset)
+    }
+
+    fn fallocate(&self, ino: u64, offset: i64, length: i64, mode: i32) -> Result<()> {
+        let file = self.backing_file(ino, File::options().write(true))?;
+        let err = unsafe { libc::fallocate(file.as_raw_fd(), mode, offset, length) };
+        if err != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn copy_file_range(
+        &self,
+        ino_in: u64,
+        mut offset_in: i64,
+        ino_out: u64,
+        mut offset_out: i64,
+        len: u64,
+        flags: u32,
+    ) -> Result<u32> {
+        let file_in = self.backing_file(ino_in, File::options().read(true))?;
+        let file_out = self.backing_file(ino_out, File::options().write(true))?;
+        // the reply can't express more than `u32::MAX` bytes, the caller loops for the rest
+        let len = len.min(u32::MAX as u64) as usize;
+        // both files live in `source` so the kernel can do a server side copy or reflink there
+        let copied = unsafe {
+            libc::copy_file_range(
+                file_in.as_raw_fd(),
+                &mut offset_in,
+                file_out.as_raw_fd(),
+                &mut offset_out,
+                len,
+                flags,
+            )
+        };
+        if copied < 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(copied as u32)
+    }
+
+    /// Show the file `ino` in the tag directory `newparent` by adding the tags of `newparent`
+    ///
+    /// The link shares the name of the backing file, other names are refused with `EPERM` just
+    /// like a file system without hard links would. Tag directories can't be linked either.
+    fn link(&self, ino: u64, newparent: u64, newname: &OsStr) -> Result<FileAttr> {
+        let file = match self.db.entry(ino)? {
+            Entry::File(file) => file.to_string_lossy().to_string(),
+            Entry::Tags(_) | Entry::Trash => return Err(Error::StdC(EPERM)),
+        };
+        let newtags = self.dir_tags(newparent)?;
+        if file.contains('/') || newname != OsStr::new(&file) {
+            return Err(Error::StdC(EPERM));
+        }
+        let current = self.db.file_tags(&file)?;
+        if newtags.is_subset(&current) {
+            return Err(Error::StdC(libc::EEXIST));
+        }
+        self.db
+            .transaction(|db| db.add_tags_to_file(newtags.difference(&current), &file))?;
+        self.sync_xdg_tags(&file);
+        Ok(file_attr_of_file(ino, self.find_file(&file)?))
+    }
+
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr) -> Result<FileAttr> {
         let tags = match self.db.entry(parent) {
             Ok(Entry::Tags(tags)) => tags,
+            Ok(Entry::Trash) => {
+                self.check_access(req, parent, libc::X_OK)?;
+                let key = trash_key(name);
+                if !self.db.is_trashed(&key)? {
+                    return Err(Error::StdC(ENOENT));
+                }
+                let ino = self.db.inode_or_create(&Entry::File(key.clone().into()))?;
+                return Ok(file_attr_of_file(ino, self.find_file(key)?));
+            }
             Ok(Entry::File(_)) | Err(_) => {
                 return Err(Error::StdC(EINVAL));
             }
         };
-        // is it a file?
-        if let Ok(path) = self.source.join(name).canonicalize() {
-            let ino = self.db.inode(&Entry::from(path.as_ref()))?;
+        self.check_access(req, parent, libc::X_OK)?;
+        self.lookup_entry(&tags, name)
+    }
+
+    /// The file or tag directory `name` in the tag directory `tags`
+    fn lookup_entry(&self, tags: &BTreeSet<Tag>, name: &OsStr) -> Result<FileAttr> {
+        if tags.is_empty() && name == TRASH_NAME {
+            let ino = self.db.inode_or_create(&Entry::Trash)?;
+            return Ok(self.trash_attr(ino));
+        }
+        // is it a file? Imported symlinks are known by their own name, not the one of the target
+        if self.source.join(name).is_file() {
+            let ino = self.db.inode_or_create(&Entry::File(name.to_os_string()))?;
             let file_tags = self.db.file_tags(name.to_string_lossy())?;
-            return if tags.is_subset(&file_tags) {
-                Ok(file_attr_of_file(ino, path))
+            return if self.is_ignored(name) || tags.is_subset(&file_tags) {
+                Ok(file_attr_of_file(ino, self.find_file(name)?))
             } else {
                 Err(Error::StdC(ENOENT))
             };
         }
         // is it a tag?
-        for row in self.db.sub_tags(&tags)? {
+        for row in self.db.sub_tags(tags)? {
             if row == name.to_string_lossy() {
                 let mut tags = tags.clone();
                 tags.insert(row);
-                let ino = self.db.inode_or_create(&Entry::Tags(tags))?;
-                return Ok(file_attr_of_file(ino, &self.source));
+                let ino = self.db.inode_or_create(&Entry::Tags(tags.clone()))?;
+                return self.tags_attr(ino, &tags);
             }
         }
         Err(Error::StdC(ENOENT))
@@ -76,24 +977,38 @@ impl fuser::Filesystem for TagsFs {
     fn init(
         &mut self,
         _req: &Request<'_>,
-        _config: &mut fuser::KernelConfig,
+        config: &mut fuser::KernelConfig,
     ) -> std::result::Result<(), c_int> {
         trace!("init");
         let root_entry = Entry::Tags(BTreeSet::new());
         // TODO properly create db if root isn't in it
         let root_ino = self.db.inode(&root_entry).unwrap();
         assert_eq!(root_ino, fuser::FUSE_ROOT_ID);
+        // let the kernel forward fcntl locks instead of only handling them locally. flock locks
+        // stay local: fuser doesn't tell them apart from fcntl locks in `setlk`, and Linux keeps
+        // the two independent of each other
+        if let Err(unsupported) = config.add_capabilities(fuser::consts::FUSE_POSIX_LOCKS) {
+            warn!("kernel doesn't support lock capabilities {unsupported:#x}");
+        }
+        // always use readdirplus, it saves a lookup per entry when listing a directory
+        if let Err(unsupported) = config.add_capabilities(fuser::consts::FUSE_DO_READDIRPLUS) {
+            warn!("kernel doesn't support readdirplus {unsupported:#x}");
+        }
         Ok(())
     }
 
     fn destroy(&mut self) {
         trace!("destroy");
+        if let Some(hook) = self.destroy_hook.take() {
+            hook();
+        }
     }
 
     fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
         trace!("lookup {parent} {name:?}");
+        self.reload_rules();
         match self.lookup(req, parent, name) {
-            Ok(attr) => reply.entry(&Duration::from_secs(0), &attr, 0),
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
             Err(Error::StdC(errno)) => reply.error(errno),
             Err(_) => reply.error(ENODEV),
         }
@@ -108,24 +1023,23 @@ impl fuser::Filesystem for TagsFs {
         match self.db.entry(ino) {
             Ok(Entry::File(name)) => {
                 if let Ok(path) = self.find_file(name) {
-                    reply.attr(&Duration::from_secs(0), &file_attr_of_file(ino, path));
+                    reply.attr(&TTL, &file_attr_of_file(ino, path));
                 } else {
                     reply.error(ENOENT);
                 }
             }
-            Ok(Entry::Tags(_)) => {
-                reply.attr(
-                    &Duration::from_secs(0),
-                    &file_attr_of_file(ino, &self.source),
-                );
-            }
+            Ok(Entry::Tags(tags)) => match self.tags_attr(ino, &tags) {
+                Ok(attr) => reply.attr(&TTL, &attr),
+                Err(err) => reply.error(err.errno()),
+            },
+            Ok(Entry::Trash) => reply.attr(&TTL, &self.trash_attr(ino)),
             Err(_) => reply.error(ENOENT),
         }
     }
 
     fn setattr(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         mode: Option<u32>,
         uid: Option<u32>,
@@ -142,8 +1056,18 @@ impl fuser::Filesystem for TagsFs {
         reply: fuser::ReplyAttr,
     ) {
         trace!("setattr");
-        // currently only allow setting attributes of files since all tags show the attributes of
-        // the source directory
+        if let Err(err) = self.check_writable() {
+            reply.error(err.errno());
+            return;
+        }
+        // tag directories only store permissions, owner and modification time
+        if let Ok(Entry::Tags(tags)) = self.db.entry(ino) {
+            match self.set_tags_attr(req, ino, &tags, (mode, uid, gid), mtime) {
+                Ok(attr) => reply.attr(&TTL, &attr),
+                Err(err) => reply.error(err.errno()),
+            }
+            return;
+        }
         let path = if let Ok(Entry::File(name)) = self.db.entry(ino) {
             if let Ok(path) = self.find_file(name) {
                 path
@@ -161,6 +1085,28 @@ impl fuser::Filesystem for TagsFs {
         };
         let mut attr = file_attr_of_file(ino, &path);
 
+        if let Err(err) = check_chmod_chown(req, &attr, mode, uid, gid) {
+            reply.error(err.errno());
+            return;
+        }
+        let is_owner = req.uid() == 0 || req.uid() == attr.uid;
+        // setting explicit timestamps is reserved to the owner
+        let specific_time = matches!(atime, Some(TimeOrNow::SpecificTime(_)))
+            || matches!(mtime, Some(TimeOrNow::SpecificTime(_)));
+        if specific_time && !is_owner {
+            reply.error(libc::EPERM);
+            return;
+        }
+        // truncating or touching to now only needs write permission
+        let touch_now =
+            matches!(atime, Some(TimeOrNow::Now)) || matches!(mtime, Some(TimeOrNow::Now));
+        if size.is_some() || (touch_now && !is_owner) {
+            if let Err(err) = check_permission(req, &attr, libc::W_OK) {
+                reply.error(err.errno());
+                return;
+            }
+        }
+
         if let Some(mode) = mode {
             let perm = PermissionsExt::from_mode(mode);
             fs::set_permissions(path, perm).unwrap();
@@ -225,7 +1171,7 @@ impl fuser::Filesystem for TagsFs {
         // chgtime: Option<SystemTime>, ?!?
         // bkuptime: Option<SystemTime>, ?!?
         // flags: Option<u32>,
-        reply.attr(&Duration::from_secs(0), &attr);
+        reply.attr(&TTL, &attr);
     }
 
     fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: fuser::ReplyData) {
@@ -253,7 +1199,7 @@ impl fuser::Filesystem for TagsFs {
 
     fn mkdir(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
         mode: u32,
@@ -267,43 +1213,90 @@ impl fuser::Filesystem for TagsFs {
             mode,
             umask
         );
-        let ino = self.db.create_tag(name.to_string_lossy()).unwrap();
-        // TODO return actual inode of new tagset
-        reply.entry(
-            &Duration::from_secs(0),
-            &file_attr_of_file(ino, &self.source),
-            0,
-        );
-    }
-
-    /// Delete all tags of `parent` from the file `name`
-    /// Note: since we don't differentiate the order of tags there is no "last" tag we could remove
-    /// here
-    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
-        trace!("unlink(parent: {:#x?}, name: {:?})", parent, name,);
-        let tags = match self.db.entry(parent) {
+        if let Err(err) = self.check_writable() {
+            reply.error(err.errno());
+            return;
+        }
+        if let Err(err) = self.check_access(req, parent, libc::W_OK | libc::X_OK) {
+            reply.error(err.errno());
+            return;
+        }
+        let mut tags = match self.db.entry(parent) {
             Ok(Entry::Tags(tags)) => tags,
             _ => {
                 reply.error(EINVAL);
                 return;
             }
         };
-        if tags.is_empty() {
-            fs::remove_file(self.find_file(name).unwrap()).unwrap();
-        } else {
-            self.db
-                .remove_tags_from_file(&tags, name.to_string_lossy())
-                .unwrap();
+        let tag = name.to_string_lossy().to_string();
+        match self.db.create_tag(&tag) {
+            Ok(_) => {}
+            Err(Error::Database(rusqlite::Error::SqliteFailure(err, _)))
+                if err.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                reply.error(libc::EEXIST);
+                return;
+            }
+            Err(err) => {
+                reply.error(err.errno());
+                return;
+            }
+        }
+        let attr = self
+            .db
+            .set_tag_attr(
+                &tag,
+                Some(mode & !umask & 0o7777),
+                Some(req.uid()),
+                Some(req.gid()),
+                None,
+            )
+            .and_then(|()| {
+                tags.insert(tag);
+                let ino = self.db.inode_or_create(&Entry::Tags(tags.clone()))?;
+                self.tags_attr(ino, &tags)
+            });
+        match attr {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(err.errno()),
         }
-        reply.ok();
     }
 
-    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+    /// Delete all tags of `parent` from the file `name`
+    /// Note: since we don't differentiate the order of tags there is no "last" tag we could remove
+    /// here
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        trace!("unlink(parent: {:#x?}, name: {:?})", parent, name,);
+        self.reload_rules();
+        if let Err(err) = self.check_writable() {
+            reply.error(err.errno());
+            return;
+        }
+        if let Err(err) = self.check_access(req, parent, libc::W_OK | libc::X_OK) {
+            reply.error(err.errno());
+            return;
+        }
+        match TagsFs::unlink(self, parent, name) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
         trace!("rmdir(parent: {:#x?}, name: {:?})", parent, name);
-        self.db
-            .delete_tags(&BTreeSet::from([name.to_string_lossy().to_string()]))
-            .unwrap();
-        reply.ok();
+        self.reload_rules();
+        if let Err(err) = self.check_writable() {
+            reply.error(err.errno());
+            return;
+        }
+        if let Err(err) = self.check_access(req, parent, libc::W_OK | libc::X_OK) {
+            reply.error(err.errno());
+            return;
+        }
+        match TagsFs::rmdir(self, parent, name) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.errno()),
+        }
     }
 
     fn symlink(
@@ -321,11 +1314,11 @@ impl fuser::Filesystem for TagsFs {
         reply.error(EPERM);
     }
 
-    /// change set of tags, it will ignore the newname since that would require a change on the
-    /// underlying file system
+    /// Move the file `name` to another set of tags and rename its backing file if `newname`
+    /// differs
     fn rename(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
         newparent: u64,
@@ -342,42 +1335,31 @@ impl fuser::Filesystem for TagsFs {
             newname,
             flags,
         );
-        let tags = match self.db.entry(parent) {
-            Ok(Entry::Tags(tags)) => tags,
-            _ => {
-                reply.error(EINVAL);
+        self.reload_rules();
+        if let Err(err) = self.check_writable() {
+            reply.error(err.errno());
+            return;
+        }
+        for parent in [parent, newparent] {
+            if let Err(err) = self.check_access(req, parent, libc::W_OK | libc::X_OK) {
+                reply.error(err.errno());
                 return;
             }
-        };
-        let newtags = match self.db.entry(newparent) {
-            Ok(Entry::Tags(tags)) => tags,
-            _ => {
-                reply.error(EINVAL);
-                return;
+        }
+        match TagsFs::rename(self, parent, name, newparent, newname, flags) {
+            Ok(()) => {
+                // the file isn't a temporary one under its new name
+                self.temp_files.remove(name);
+                reply.ok()
             }
-        };
-
-        self.db
-            .remove_tags_from_file(
-                tags.iter().filter(|t| !newtags.contains(*t)),
-                name.to_string_lossy(),
-            )
-            .unwrap();
-
-        self.db
-            .add_tags_to_file(
-                newtags.iter().filter(|t| !tags.contains(*t)),
-                name.to_string_lossy(),
-            )
-            .unwrap();
-        reply.ok();
+            Err(err) => reply.error(err.errno()),
+        }
     }
 
-    /// all links have the same file name since they share the name of the backing file so we
-    /// ignore newname
+    /// Add the tags of `newparent` to the file `ino`, `ln file tag/` tags `file` with `tag`
     fn link(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         newparent: u64,
         newname: &OsStr,
@@ -389,27 +1371,41 @@ impl fuser::Filesystem for TagsFs {
             newparent,
             newname
         );
-        let name = match self.db.entry(ino) {
-            Ok(Entry::File(name)) => name,
-            _ => {
-                reply.error(EINVAL);
-                return;
-            }
+        self.reload_rules();
+        if let Err(err) = self.check_writable() {
+            reply.error(err.errno());
+            return;
+        }
+        if let Err(err) = self.check_access(req, newparent, libc::W_OK | libc::X_OK) {
+            reply.error(err.errno());
+            return;
+        }
+        match TagsFs::link(self, ino, newparent, newname) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: fuser::ReplyOpen) {
+        trace!("open(req, {ino}, {flags}, reply)");
+        let mut mask = match flags & libc::O_ACCMODE {
+            libc::O_RDONLY => libc::R_OK,
+            libc::O_WRONLY => libc::W_OK,
+            _ => libc::R_OK | libc::W_OK,
         };
-        let tags = match self.db.entry(newparent) {
-            Ok(Entry::Tags(tags)) => tags,
-            _ => {
-                reply.error(EINVAL);
+        if flags & libc::O_TRUNC != 0 {
+            mask |= libc::W_OK;
+        }
+        if mask & libc::W_OK != 0 {
+            if let Err(err) = self.check_writable() {
+                reply.error(err.errno());
                 return;
             }
-        };
-        self.db
-            .add_tags_to_file(tags, name.to_string_lossy())
-            .unwrap();
-    }
-
-    fn open(&mut self, _req: &Request<'_>, _ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
-        trace!("open(req, {_ino}, {_flags}, reply)");
+        }
+        if let Err(err) = self.check_access(req, ino, mask) {
+            reply.error(err.errno());
+            return;
+        }
         reply.opened(0, 0);
     }
 
@@ -456,6 +1452,10 @@ impl fuser::Filesystem for TagsFs {
             write_flags: {write_flags:#x?}, flags: {flags:#x?}, lock_owner: {lock_owner:?})",
             data.len(),
         );
+        if let Err(err) = self.check_writable() {
+            reply.error(err.errno());
+            return;
+        }
         let path = match self.db.entry(ino) {
             Ok(Entry::File(name)) => self.source.join(name).canonicalize().unwrap(),
             _ => {
@@ -471,6 +1471,7 @@ impl fuser::Filesystem for TagsFs {
         }
     }
 
+    /// Called on every `close` of a file descriptor, POSIX locks of `lock_owner` don't survive it
     fn flush(
         &mut self,
         _req: &Request<'_>,
@@ -479,11 +1480,9 @@ impl fuser::Filesystem for TagsFs {
         lock_owner: u64,
         reply: fuser::ReplyEmpty,
     ) {
-        debug!(
-            "[Not Implemented] flush(ino: {:#x?}, fh: {}, lock_owner: {:?})",
-            ino, fh, lock_owner
-        );
-        reply.error(ENOSYS);
+        trace!("flush(ino: {ino:#x?}, fh: {fh}, lock_owner: {lock_owner:?})");
+        self.locks.release_owner(ino, lock_owner);
+        reply.ok();
     }
 
     fn release(
@@ -515,9 +1514,21 @@ impl fuser::Filesystem for TagsFs {
         reply.error(ENOSYS);
     }
 
-    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
+    /// Read the whole listing, `readdir` and `readdirplus` return it in chunks
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, _flags: i32, reply: fuser::ReplyOpen) {
         trace!("opendir {ino}");
-        reply.opened(0, 0);
+        let listing = self
+            .check_access(req, ino, libc::R_OK)
+            .and_then(|()| self.listing(ino));
+        match listing {
+            Ok(listing) => {
+                let fh = self.next_fh;
+                self.next_fh += 1;
+                self.listings.insert(fh, listing);
+                reply.opened(fh, 0);
+            }
+            Err(err) => reply.error(err.errno()),
+        }
     }
 
     fn readdir(
@@ -529,60 +1540,16 @@ impl fuser::Filesystem for TagsFs {
         mut reply: fuser::ReplyDirectory,
     ) {
         trace!("readdir {ino} {fh} {offset}");
-        let entry = self.db.entry(ino);
-        let tags = match entry {
-            Ok(Entry::File(_)) => {
-                reply.error(EINVAL);
-                return;
-            }
-            Err(_) => {
-                reply.error(ENOENT);
+        let entries = match self.open_listing(ino, fh) {
+            Ok(entries) => entries,
+            Err(err) => {
+                reply.error(err.errno());
                 return;
             }
-            Ok(Entry::Tags(tags)) => tags.clone(),
         };
-        let mut cur = 0;
-        for file in std::fs::read_dir(&self.source).unwrap() {
-            cur += 1;
-            if cur <= offset {
-                continue;
-            }
-            let file = file.unwrap();
-            let path = file.path().canonicalize().unwrap();
-            let file_tags = self
-                .db
-                .file_tags(file.file_name().to_string_lossy())
-                .unwrap();
-            if !tags.is_subset(&file_tags) {
-                continue;
-            }
-            let entry = Entry::from(path.as_ref());
-            let f_ino = if let Ok(ino) = entry.inode(&self.db) {
-                ino
-            } else {
-                entry.create(&self.db).unwrap()
-            };
-            if file.file_type().unwrap().is_file() {
-                if reply.add(f_ino, cur, fuser::FileType::RegularFile, file.file_name()) {
-                    reply.ok();
-                    return;
-                }
-            }
-        }
-        for tag in self.db.sub_tags(&tags).unwrap() {
-            cur += 1;
-            if cur <= offset {
-                continue;
-            }
-            let entry = Entry::Tags(BTreeSet::from([tag.clone()]));
-            let ino = if let Ok(ino) = self.db.inode(&entry) {
-                ino
-            } else {
-                entry.create(&self.db).unwrap()
-            };
-            if reply.add(ino, cur, fuser::FileType::Directory, tag) {
-                reply.ok();
-                return;
+        for (cur, (name, attr)) in entries.iter().enumerate().skip(offset as usize) {
+            if reply.add(attr.ino, cur as i64 + 1, attr.kind, name) {
+                break;
             }
         }
         reply.ok();
@@ -594,24 +1561,34 @@ impl fuser::Filesystem for TagsFs {
         ino: u64,
         fh: u64,
         offset: i64,
-        reply: fuser::ReplyDirectoryPlus,
+        mut reply: fuser::ReplyDirectoryPlus,
     ) {
-        debug!(
-            "[Not Implemented] readdirplus(ino: {:#x?}, fh: {}, offset: {})",
-            ino, fh, offset
-        );
-        reply.error(ENOSYS);
+        trace!("readdirplus {ino} {fh} {offset}");
+        let entries = match self.open_listing(ino, fh) {
+            Ok(entries) => entries,
+            Err(err) => {
+                reply.error(err.errno());
+                return;
+            }
+        };
+        for (cur, (name, attr)) in entries.iter().enumerate().skip(offset as usize) {
+            if reply.add(attr.ino, cur as i64 + 1, name, &TTL, attr, 0) {
+                break;
+            }
+        }
+        reply.ok();
     }
 
     fn releasedir(
         &mut self,
         _req: &Request<'_>,
         _ino: u64,
-        _fh: u64,
+        fh: u64,
         _flags: i32,
         reply: fuser::ReplyEmpty,
     ) {
-        trace!("releasedir(req, {_ino}, {_fh}, {_flags:o}, reply)");
+        trace!("releasedir(req, {_ino}, {fh}, {_flags:o}, reply)");
+        self.listings.remove(&fh);
         reply.ok();
     }
 
@@ -632,71 +1609,86 @@ impl fuser::Filesystem for TagsFs {
 
     fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: fuser::ReplyStatfs) {
         trace!("statfs(_req, {_ino}, reply)");
-        reply.statfs(0, 0, 0, 0, 0, 512, 255, 0);
+        match TagsFs::statfs(self) {
+            Ok((stat, files)) => reply.statfs(
+                stat.f_blocks,
+                stat.f_bfree,
+                stat.f_bavail,
+                files,
+                stat.f_ffree,
+                stat.f_bsize as u32,
+                // file names come from `source` and tags have to fit into a single path component
+                (stat.f_namemax as u32).min(NAME_MAX),
+                stat.f_frsize as u32,
+            ),
+            Err(err) => reply.error(err.errno()),
+        }
     }
 
     fn setxattr(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         name: &OsStr,
-        _value: &[u8],
+        value: &[u8],
         flags: i32,
         position: u32,
         reply: fuser::ReplyEmpty,
     ) {
-        debug!(
-            "[Not Implemented] setxattr(ino: {:#x?}, name: {:?}, flags: {:#x?}, position: {})",
-            ino, name, flags, position
+        trace!(
+            "setxattr(ino: {ino:#x?}, name: {name:?}, flags: {flags:#x?}, position: {position})"
         );
-        reply.error(ENOSYS);
+        self.reload_rules();
+        if let Err(err) = self.check_writable() {
+            reply.error(err.errno());
+            return;
+        }
+        match TagsFs::setxattr(self, req, ino, name, value, flags) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.errno()),
+        }
     }
 
     fn getxattr(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
         name: &OsStr,
         size: u32,
         reply: fuser::ReplyXattr,
     ) {
-        debug!(
-            "[Not Implemented] getxattr(ino: {:#x?}, name: {:?}, size: {})",
-            ino, name, size
-        );
-        reply.error(ENOSYS);
+        trace!("getxattr(ino: {ino:#x?}, name: {name:?}, size: {size})");
+        reply_xattr(reply, size, TagsFs::getxattr(self, req, ino, name));
     }
 
     fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: fuser::ReplyXattr) {
-        debug!(
-            "[Not Implemented] listxattr(ino: {:#x?}, size: {})",
-            ino, size
-        );
-        reply.error(ENOSYS);
+        trace!("listxattr(ino: {ino:#x?}, size: {size})");
+        reply_xattr(reply, size, TagsFs::listxattr(self, ino));
     }
 
-    fn removexattr(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        name: &OsStr,
-        reply: fuser::ReplyEmpty,
-    ) {
-        debug!(
-            "[Not Implemented] removexattr(ino: {:#x?}, name: {:?})",
-            ino, name
-        );
-        reply.error(ENOSYS);
+    fn removexattr(&mut self, req: &Request<'_>, ino: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        trace!("removexattr(ino: {ino:#x?}, name: {name:?})");
+        if let Err(err) = self.check_writable() {
+            reply.error(err.errno());
+            return;
+        }
+        match TagsFs::removexattr(self, req, ino, name) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.errno()),
+        }
     }
 
-    fn access(&mut self, _req: &Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
-        debug!("[Not Implemented] access(ino: {:#x?}, mask: {})", ino, mask);
-        reply.error(ENOSYS);
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        trace!("access(ino: {ino:#x?}, mask: {mask})");
+        match self.check_access(req, ino, mask) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.errno()),
+        }
     }
 
     fn create(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
         mode: u32,
@@ -708,23 +1700,26 @@ impl fuser::Filesystem for TagsFs {
             "create(parent: {parent:#x?}, name: {name:?}, mode: {mode:o}, \
             umask: {umask:#x?}, flags: {flags:#x?})",
         );
+        self.reload_rules();
+        if let Err(err) = self.check_writable() {
+            reply.error(err.errno());
+            return;
+        }
+        if let Err(err) = self
+            .check_access(req, parent, libc::W_OK | libc::X_OK)
+            .and_then(|()| self.dir_tags(parent))
+        {
+            reply.error(err.errno());
+            return;
+        }
         let source_path = self.source.join(name);
         if source_path.is_file() {
             reply.error(libc::EEXIST);
             return;
         }
 
-        let c_path =
-            unsafe { CString::from_vec_unchecked(source_path.as_os_str().as_bytes().to_vec()) };
-        let new_fd = unsafe { libc::creat(c_path.as_ptr(), mode & !umask) };
-        if new_fd == 0 {
-            reply.error(EPERM);
-            return;
-        }
-
-        let err = unsafe { libc::close(new_fd) };
-        if err != 0 {
-            reply.error(err);
+        if let Err(err) = create_owned(&source_path, mode & !umask, req.uid(), req.gid()) {
+            reply.error(err.errno());
             return;
         }
         let ino = self
@@ -734,15 +1729,21 @@ impl fuser::Filesystem for TagsFs {
         let attr = file_attr_of_file(ino, &source_path);
         trace!("{ino} {attr:?}");
         let tags = match self.db.entry(parent) {
-            Ok(Entry::Tags(tags)) => tags,
+            Ok(Entry::Tags(tags)) if !self.is_ignored(name) => tags,
             _ => BTreeSet::new(),
         };
         trace!("{tags:?}");
-        self.db
-            .add_tags_to_file(tags, name.to_string_lossy())
-            .unwrap();
+        if self.is_ignored(name) {
+            self.temp_files.insert(name.to_os_string());
+        }
+        if !tags.is_empty() {
+            self.db
+                .add_tags_to_file(tags, name.to_string_lossy())
+                .unwrap();
+            self.sync_xdg_tags(&name.to_string_lossy());
+        }
 
-        reply.created(&Duration::from_secs(0), &attr, 0, 0, 0);
+        reply.created(&TTL, &attr, 0, 0, 0);
         trace!("finished create");
     }
 
@@ -758,12 +1759,21 @@ impl fuser::Filesystem for TagsFs {
         pid: u32,
         reply: fuser::ReplyLock,
     ) {
-        debug!(
-            "[Not Implemented] getlk(ino: {:#x?}, fh: {}, lock_owner: {}, start: {}, \
-            end: {}, typ: {}, pid: {})",
-            ino, fh, lock_owner, start, end, typ, pid
+        trace!(
+            "getlk(ino: {ino:#x?}, fh: {fh}, lock_owner: {lock_owner}, start: {start}, \
+            end: {end}, typ: {typ}, pid: {pid})"
         );
-        reply.error(ENOSYS);
+        let lock = Lock {
+            owner: lock_owner,
+            start,
+            end,
+            typ,
+            pid,
+        };
+        match self.locks.conflicting(ino, &lock) {
+            Some(held) => reply.locked(held.start, held.end, held.typ, held.pid),
+            None => reply.locked(start, end, libc::F_UNLCK, 0),
+        }
     }
 
     fn setlk(
@@ -779,12 +1789,23 @@ impl fuser::Filesystem for TagsFs {
         sleep: bool,
         reply: fuser::ReplyEmpty,
     ) {
-        debug!(
-            "[Not Implemented] setlk(ino: {:#x?}, fh: {}, lock_owner: {}, start: {}, \
-            end: {}, typ: {}, pid: {}, sleep: {})",
-            ino, fh, lock_owner, start, end, typ, pid, sleep
+        trace!(
+            "setlk(ino: {ino:#x?}, fh: {fh}, lock_owner: {lock_owner}, start: {start}, \
+            end: {end}, typ: {typ}, pid: {pid}, sleep: {sleep})"
         );
-        reply.error(ENOSYS);
+        let lock = Lock {
+            owner: lock_owner,
+            start,
+            end,
+            typ,
+            pid,
+        };
+        match self.locks.set(ino, lock) {
+            Ok(()) => reply.ok(),
+            Err(Error::StdC(libc::EAGAIN)) if sleep => self.locks.wait(ino, lock, reply),
+            Err(Error::StdC(errno)) => reply.error(errno),
+            Err(_) => reply.error(EINVAL),
+        }
     }
 
     fn bmap(
@@ -836,12 +1857,18 @@ impl fuser::Filesystem for TagsFs {
         mode: i32,
         reply: fuser::ReplyEmpty,
     ) {
-        debug!(
-            "[Not Implemented] fallocate(ino: {:#x?}, fh: {}, offset: {}, \
-            length: {}, mode: {:o})",
-            ino, fh, offset, length, mode
+        trace!(
+            "fallocate(ino: {ino:#x?}, fh: {fh}, offset: {offset}, \
+            length: {length}, mode: {mode:o})"
         );
-        reply.error(ENOSYS);
+        if let Err(err) = self.check_writable() {
+            reply.error(err.errno());
+            return;
+        }
+        match TagsFs::fallocate(self, ino, offset, length, mode) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err.errno()),
+        }
     }
 
     fn lseek(
@@ -853,11 +1880,11 @@ impl fuser::Filesystem for TagsFs {
         whence: i32,
         reply: fuser::ReplyLseek,
     ) {
-        debug!(
-            "[Not Implemented] lseek(ino: {:#x?}, fh: {}, offset: {}, whence: {})",
-            ino, fh, offset, whence
-        );
-        reply.error(ENOSYS);
+        trace!("lseek(ino: {ino:#x?}, fh: {fh}, offset: {offset}, whence: {whence})");
+        match TagsFs::lseek(self, ino, offset, whence) {
+            Ok(offset) => reply.offset(offset),
+            Err(err) => reply.error(err.errno()),
+        }
     }
 
     fn copy_file_range(
@@ -873,18 +1900,227 @@ impl fuser::Filesystem for TagsFs {
         flags: u32,
         reply: fuser::ReplyWrite,
     ) {
-        debug!(
-            "[Not Implemented] copy_file_range(ino_in: {:#x?}, fh_in: {}, \
-            offset_in: {}, ino_out: {:#x?}, fh_out: {}, offset_out: {}, \
-            len: {}, flags: {})",
-            ino_in, fh_in, offset_in, ino_out, fh_out, offset_out, len, flags
+        trace!(
+            "copy_file_range(ino_in: {ino_in:#x?}, fh_in: {fh_in}, \
+            offset_in: {offset_in}, ino_out: {ino_out:#x?}, fh_out: {fh_out}, \
+            offset_out: {offset_out}, len: {len}, flags: {flags})"
         );
-        reply.error(ENOSYS);
+        if let Err(err) = self.check_writable() {
+            reply.error(err.errno());
+            return;
+        }
+        match TagsFs::copy_file_range(self, ino_in, offset_in, ino_out, offset_out, len, flags) {
+            Ok(written) => reply.written(written),
+            Err(err) => reply.error(err.errno()),
+        }
+    }
+}
+
+/// Attributes of a tag directory with `files` files and `sub_tags` sub directories
+///
+/// `source` are the attributes of `source` and `tag_attrs` the ones of each tag of the
+/// directory, the first tag decides the owner.
+fn merge_tag_attrs(source: FileAttr, tag_attrs: &[TagAttr], files: u64, sub_tags: u64) -> FileAttr {
+    let mut attr = source;
+    attr.perm = 0o7777;
+    for (i, tag_attr) in tag_attrs.iter().enumerate() {
+        attr.perm &= tag_attr.mode.map_or(source.perm, |mode| mode as u16) & 0o7777;
+        if i == 0 {
+            attr.uid = tag_attr.uid.unwrap_or(source.uid);
+            attr.gid = tag_attr.gid.unwrap_or(source.gid);
+            attr.crtime = tag_attr.crtime.unwrap_or(source.crtime);
+            attr.mtime = tag_attr.mtime.unwrap_or(source.mtime);
+            attr.ctime = tag_attr.ctime.unwrap_or(source.ctime);
+        } else {
+            attr.crtime = attr.crtime.max(tag_attr.crtime.unwrap_or(source.crtime));
+            attr.mtime = attr.mtime.max(tag_attr.mtime.unwrap_or(source.mtime));
+            attr.ctime = attr.ctime.max(tag_attr.ctime.unwrap_or(source.ctime));
+        }
+    }
+    attr.nlink = 2 + sub_tags as u32;
+    attr.size = files + sub_tags;
+    attr
+}
+
+/// Key of a trashed file in the database, its path relative to `source`
+fn trash_key(name: &OsStr) -> String {
+    format!("{TRASH_DIR}/{}", name.to_string_lossy())
+}
+
+/// Move `file` from the tag directory `from` to `to`
+fn retag(db: &TagsFsDb, file: &str, from: &BTreeSet<Tag>, to: &BTreeSet<Tag>) -> Result<()> {
+    let current = db.file_tags(file)?;
+    db.remove_tags_from_file(from.difference(to), file)?;
+    db.add_tags_to_file(to.difference(from).filter(|t| !current.contains(*t)), file)
+}
+
+/// Answer a `getxattr` or `listxattr`, a `size` of 0 asks for the length of the value only
+fn reply_xattr(reply: fuser::ReplyXattr, size: u32, value: Result<Vec<u8>>) {
+    match value {
+        Ok(value) if size == 0 => reply.size(value.len() as u32),
+        Ok(value) if value.len() > size as usize => reply.error(libc::ERANGE),
+        Ok(value) => reply.data(&value),
+        Err(err) => reply.error(err.errno()),
+    }
+}
+
+fn is_reserved_xattr(name: &OsStr) -> bool {
+    name == TAGS_XATTR || name.as_bytes().starts_with(TAGSFS_XATTR_PREFIX.as_bytes())
+}
+
+/// Match `name` against a shell style `pattern` with `*` and `?` wildcards
+pub(crate) fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // position of the last `*` and the part of `name` it matched up to now
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // let the last `*` match one more byte and retry from there
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Parse a comma or newline separated list of tags as written to `user.tags`
+fn parse_tags(value: &[u8]) -> Result<BTreeSet<Tag>> {
+    let value = std::str::from_utf8(value).map_err(|_| Error::StdC(EINVAL))?;
+    let tags: BTreeSet<Tag> = value
+        .split([',', '\n'])
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(String::from)
+        .collect();
+    if !tags.iter().all(|tag| is_valid_tag(tag)) {
+        return Err(Error::StdC(EINVAL));
+    }
+    Ok(tags)
+}
+
+/// Whether `tag` is usable as a directory name
+pub fn is_valid_tag(tag: &str) -> bool {
+    !matches!(tag, "" | "." | "..") && !tag.contains('/') && tag.len() <= NAME_MAX as usize
+}
+
+/// Check the permission bits of `attr` against the credentials of the caller of `req` the same
+/// way the kernel does for `access(2)`
+fn check_permission(req: &Request<'_>, attr: &FileAttr, mask: i32) -> Result<()> {
+    let mask = (mask & 0o7) as u16;
+    if mask == 0 {
+        return Ok(());
+    }
+    let granted = if req.uid() == 0 {
+        // root may read and write anything but only execute what anyone may execute
+        if mask & libc::X_OK as u16 == 0
+            || attr.kind == fuser::FileType::Directory
+            || attr.perm & 0o111 != 0
+        {
+            return Ok(());
+        }
+        0
+    } else if req.uid() == attr.uid {
+        attr.perm >> 6 & 0o7
+    } else if caller_in_group(req, attr.gid) {
+        attr.perm >> 3 & 0o7
+    } else {
+        attr.perm & 0o7
+    };
+    if granted & mask == mask {
+        Ok(())
+    } else {
+        Err(Error::StdC(libc::EACCES))
+    }
+}
+
+/// Only the owner may `chmod`, only root may give things away and owners may only change the
+/// group to one of their own
+fn check_chmod_chown(
+    req: &Request<'_>,
+    attr: &FileAttr,
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+) -> Result<()> {
+    let caller = req.uid();
+    if caller == 0 {
+        return Ok(());
+    }
+    let foreign_uid = uid.is_some_and(|uid| uid != attr.uid);
+    let foreign_gid = gid
+        .is_some_and(|gid| gid != attr.gid && (caller != attr.uid || !caller_in_group(req, gid)));
+    if (mode.is_some() && caller != attr.uid) || foreign_uid || foreign_gid {
+        return Err(Error::StdC(libc::EPERM));
+    }
+    Ok(())
+}
+
+/// Create the empty file `path` owned by `uid` and `gid` instead of the user running tagsfs
+///
+/// Only root can give files away, otherwise the file keeps the owner the daemon has.
+fn create_owned(path: &Path, mode: u32, uid: u32, gid: u32) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|_| Error::StdC(EINVAL))?;
+    let fd = unsafe { libc::creat(c_path.as_ptr(), mode) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
     }
+    let chowned = match unsafe { libc::fchown(fd, uid, gid) } {
+        0 => Ok(()),
+        _ => match Error::last_os_error() {
+            err if err.errno() == EPERM => {
+                debug!("can't give {path:?} to {uid}:{gid}");
+                Ok(())
+            }
+            err => Err(err),
+        },
+    };
+    let closed = match unsafe { libc::close(fd) } {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error()),
+    };
+    chowned.and(closed)
+}
+
+fn caller_in_group(req: &Request<'_>, gid: u32) -> bool {
+    req.gid() == gid || supplementary_groups(req.pid()).contains(&gid)
+}
+
+/// Supplementary groups of the process `pid`, the kernel only tells us its primary group
+fn supplementary_groups(pid: u32) -> Vec<u32> {
+    fs::read_to_string(format!("/proc/{pid}/status"))
+        .ok()
+        .and_then(|status| {
+            status.lines().find_map(|line| {
+                line.strip_prefix("Groups:").map(|groups| {
+                    groups
+                        .split_whitespace()
+                        .filter_map(|gid| gid.parse().ok())
+                        .collect()
+                })
+            })
+        })
+        .unwrap_or_default()
 }
 
 fn file_attr_of_file<P: AsRef<Path>>(ino: u64, path: P) -> FileAttr {
-    let metadata = std::fs::metadata(path).unwrap();
+    file_attr_of_metadata(ino, &std::fs::metadata(path).unwrap())
+}
+
+fn file_attr_of_metadata(ino: u64, metadata: &fs::Metadata) -> FileAttr {
     let ctime = SystemTime::UNIX_EPOCH + Duration::from_nanos(metadata.ctime_nsec() as u64);
     FileAttr {
         ino,
@@ -915,13 +2151,15 @@ fn file_attr_of_file<P: AsRef<Path>>(ino: u64, path: P) -> FileAttr {
 pub enum Entry {
     File(OsString),
     Tags(BTreeSet<String>),
+    /// The virtual `/.trash` directory, the files in it are [`Entry::File`]s below `TRASH_DIR`
+    Trash,
 }
 
 impl Entry {
     fn file_type(&self) -> fuser::FileType {
         match self {
             Entry::File(_) => fuser::FileType::RegularFile,
-            Entry::Tags(_) => fuser::FileType::Directory,
+            Entry::Tags(_) | Entry::Trash => fuser::FileType::Directory,
         }
     }
 
@@ -952,6 +2190,7 @@ impl Entry {
         match self {
             Entry::File(name) => ("file", name.to_string_lossy()),
             Entry::Tags(tags) => ("tags", Cow::Owned(tags.iter().sorted().join("/"))),
+            Entry::Trash => ("trash", Cow::Borrowed("")),
         }
     }
 }
@@ -962,3 +2201,254 @@ impl From<&Path> for Entry {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        wildcard_match(pattern.as_bytes(), name.as_bytes())
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches(".*.sw?", ".notes.txt.swp"));
+        assert!(!matches(".*.sw?", "notes.txt.swp"));
+        assert!(matches("*~", "notes.txt~"));
+        assert!(matches("*~", "~"));
+        assert!(!matches("*~", "notes.txt"));
+        assert!(matches("#*#", "#notes#"));
+        assert!(!matches("#*#", "#"));
+        assert!(matches("4913", "4913"));
+        assert!(!matches("4913", "49131"));
+        assert!(matches("*", ""));
+        assert!(matches("a*b*c", "aXbYbZc"));
+        assert!(!matches("a*b*c", "aXbYbZ"));
+        assert!(!matches("?", ""));
+    }
+
+    fn set(tags: &[&str]) -> BTreeSet<Tag> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    /// A file system serving `dir/source` with the files `files` and their tags
+    fn tags_fs(dir: &crate::TestDir, files: &[(&str, &[&str])]) -> TagsFs {
+        std::fs::create_dir_all(dir.join("source")).unwrap();
+        let fs = TagsFs::from_db(TagsFsDb::in_memory(), dir.join("source"));
+        for (file, tags) in files {
+            dir.touch(&format!("source/{file}"));
+            fs.db.add_tags_to_file(tags.iter(), file).unwrap();
+        }
+        fs
+    }
+
+    /// Inode of the tag directory `tags`
+    fn tag_dir(fs: &TagsFs, tags: &[&str]) -> u64 {
+        fs.db.inode_or_create(&Entry::Tags(set(tags))).unwrap()
+    }
+
+    fn errno<T>(result: Result<T>) -> Option<i32> {
+        result.err().map(|err| err.errno())
+    }
+
+    #[test]
+    fn rmdir_only_untags_below_the_parent() {
+        let dir = crate::TestDir::new("filesystem-rmdir");
+        let mut fs = tags_fs(
+            &dir,
+            &[
+                ("a.jpg", &["italy", "rome"]),
+                ("b.jpg", &["rome"]),
+                ("c.jpg", &["spain"]),
+            ],
+        );
+        let italy = tag_dir(&fs, &["italy"]);
+        let spain = tag_dir(&fs, &["spain"]);
+        let root = tag_dir(&fs, &[]);
+        assert_eq!(
+            errno(fs.rmdir(italy, "rome".as_ref())),
+            Some(libc::ENOTEMPTY)
+        );
+        assert_eq!(errno(fs.rmdir(spain, "rome".as_ref())), Some(libc::EBUSY));
+        assert_eq!(fs.db.file_tags("a.jpg").unwrap(), set(&["italy", "rome"]));
+
+        fs.rmdir_untags = true;
+        fs.rmdir(italy, "rome".as_ref()).unwrap();
+        assert_eq!(fs.db.file_tags("a.jpg").unwrap(), set(&["italy"]));
+        assert_eq!(fs.db.file_tags("b.jpg").unwrap(), set(&["rome"]));
+        fs.rmdir(spain, "rome".as_ref()).unwrap();
+        assert!(fs.db.tag_id("rome").is_ok());
+        fs.rmdir(root, "rome".as_ref()).unwrap();
+        assert!(fs.db.file_tags("b.jpg").unwrap().is_empty());
+        assert!(fs.db.tag_id("rome").is_err());
+    }
+
+    /// Set the modification time of `tags` back to the epoch
+    fn age(fs: &TagsFs, tags: &[&str]) {
+        for tag in tags {
+            let epoch = Some(SystemTime::UNIX_EPOCH);
+            fs.db.set_tag_attr(tag, None, None, None, epoch).unwrap();
+        }
+    }
+
+    fn touched(fs: &TagsFs, tag: &str) -> bool {
+        fs.db.tag_attr(tag).unwrap().mtime > Some(SystemTime::UNIX_EPOCH)
+    }
+
+    #[test]
+    fn trash_and_restore_keep_tags() {
+        let dir = crate::TestDir::new("filesystem-trash");
+        let fs = tags_fs(&dir, &[("a.jpg", &["italy"]), ("b.jpg", &["italy"])]);
+        dir.touch(&format!("source/{TRASH_DIR}/a.jpg"));
+        age(&fs, &["italy"]);
+        fs.trash("a.jpg".as_ref()).unwrap();
+        assert!(touched(&fs, "italy"));
+        assert!(!dir.join("source/a.jpg").exists());
+        assert!(dir.join(format!("source/{TRASH_DIR}/a.jpg.1")).is_file());
+        assert_eq!(fs.db.files_with_tags(&set(&["italy"])).unwrap().len(), 1);
+
+        age(&fs, &["italy"]);
+        fs.restore("a.jpg.1".as_ref(), &set(&["rome"]), "c.jpg".as_ref())
+            .unwrap();
+        assert!(touched(&fs, "italy"));
+        assert!(dir.join("source/c.jpg").is_file());
+        assert_eq!(fs.db.file_tags("c.jpg").unwrap(), set(&["italy", "rome"]));
+        assert!(!fs.db.is_trashed(&trash_key("a.jpg.1".as_ref())).unwrap());
+        let restore = fs.restore("a.jpg.1".as_ref(), &set(&[]), "d.jpg".as_ref());
+        assert_eq!(errno(restore), Some(ENOENT));
+    }
+
+    #[test]
+    fn rename_flags() {
+        let dir = crate::TestDir::new("filesystem-rename-flags");
+        let fs = tags_fs(
+            &dir,
+            &[("a.txt", &["notes"]), ("b.txt", &["notes", "work"])],
+        );
+        std::fs::write(dir.join("source/a.txt"), "a").unwrap();
+        let notes = tag_dir(&fs, &["notes"]);
+        let rename = |from: &str, to: &str, flags| {
+            fs.rename(notes, from.as_ref(), notes, to.as_ref(), flags)
+        };
+        let both = libc::RENAME_NOREPLACE | libc::RENAME_EXCHANGE;
+        assert_eq!(errno(rename("a.txt", "b.txt", both)), Some(EINVAL));
+        let noreplace = rename("a.txt", "b.txt", libc::RENAME_NOREPLACE);
+        assert_eq!(errno(noreplace), Some(libc::EEXIST));
+        let exchange = rename("a.txt", "c.txt", libc::RENAME_EXCHANGE);
+        assert_eq!(errno(exchange), Some(ENOENT));
+
+        age(&fs, &["notes", "work"]);
+        rename("a.txt", "b.txt", libc::RENAME_EXCHANGE).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("source/b.txt")).unwrap(),
+            "a"
+        );
+        assert_eq!(fs.db.file_tags("b.txt").unwrap(), set(&["notes"]));
+        assert_eq!(fs.db.file_tags("a.txt").unwrap(), set(&["notes", "work"]));
+        assert!(touched(&fs, "notes") && touched(&fs, "work"));
+    }
+
+    #[test]
+    fn replacing_a_file_keeps_the_tags_of_both() {
+        let dir = crate::TestDir::new("filesystem-replace");
+        let fs = tags_fs(
+            &dir,
+            &[
+                ("draft.txt", &["notes", "draft"]),
+                ("b.txt", &["notes", "work"]),
+            ],
+        );
+        std::fs::write(dir.join("source/draft.txt"), "new").unwrap();
+        let draft_ino = fs
+            .db
+            .inode_or_create(&Entry::File("draft.txt".into()))
+            .unwrap();
+        let notes = tag_dir(&fs, &["notes"]);
+        age(&fs, &["notes", "draft", "work"]);
+        fs.rename(notes, "draft.txt".as_ref(), notes, "b.txt".as_ref(), 0)
+            .unwrap();
+        assert!(!dir.join("source/draft.txt").exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("source/b.txt")).unwrap(),
+            "new"
+        );
+        assert!(fs.db.file_tags("draft.txt").unwrap().is_empty());
+        assert_eq!(
+            fs.db.file_tags("b.txt").unwrap(),
+            set(&["draft", "notes", "work"])
+        );
+        assert_eq!(
+            fs.db.inode(&Entry::File("b.txt".into())).unwrap(),
+            draft_ino
+        );
+        assert!(touched(&fs, "draft") && touched(&fs, "work"));
+    }
+
+    #[test]
+    fn created_files_belong_to_the_caller() {
+        let dir = crate::TestDir::new("filesystem-create");
+        // without root the file can only be given to ourselves
+        let (uid, gid) = match unsafe { libc::geteuid() } {
+            0 => (4242, 4343),
+            uid => (uid, unsafe { libc::getegid() }),
+        };
+        create_owned(&dir.join("new.txt"), 0o640, uid, gid).unwrap();
+        let metadata = std::fs::metadata(dir.join("new.txt")).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (uid, gid));
+        assert_eq!(metadata.mode() & 0o777, 0o640);
+    }
+
+    #[test]
+    fn symlinked_files_are_listed() {
+        let dir = crate::TestDir::new("filesystem-symlinks");
+        let target = dir.touch("elsewhere/rome.jpg");
+        std::fs::create_dir(dir.join("source")).unwrap();
+        std::os::unix::fs::symlink(&target, dir.join("source/rome.jpg")).unwrap();
+        std::os::unix::fs::symlink(dir.join("gone"), dir.join("source/dangling.jpg")).unwrap();
+        let fs = TagsFs::from_db(TagsFsDb::in_memory(), dir.join("source"));
+        fs.db.add_tags_to_file(["italy"], "rome.jpg").unwrap();
+
+        let names = |tags: &[&str]| {
+            let tags = tags.iter().map(|tag| tag.to_string()).collect();
+            let entries = fs.dir_entries(&tags).unwrap();
+            entries
+                .into_iter()
+                .map(|(name, _)| name.to_string_lossy().to_string())
+                .sorted()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&[]), [".trash", "italy", "rome.jpg"]);
+        assert_eq!(names(&["italy"]), ["rome.jpg"]);
+        assert_eq!(fs.statfs().unwrap().1, 2);
+    }
+
+    #[test]
+    fn symlinks_are_looked_up_by_their_name() {
+        let dir = crate::TestDir::new("filesystem-lookup-symlink");
+        let fs = tags_fs(&dir, &[("rome.jpg", &["italy"])]);
+        std::fs::write(dir.join("photos-rome.jpg"), "imported").unwrap();
+        std::os::unix::fs::symlink(dir.join("photos-rome.jpg"), dir.join("source/rome.2.jpg"))
+            .unwrap();
+        fs.db.add_tags_to_file(["italy"], "rome.2.jpg").unwrap();
+
+        let italy = set(&["italy"]);
+        let attr = fs.lookup_entry(&italy, "rome.2.jpg".as_ref()).unwrap();
+        let ino = fs.db.inode(&Entry::File("rome.2.jpg".into())).unwrap();
+        assert_eq!(attr.ino, ino);
+        assert_eq!(attr.size, "imported".len() as u64);
+        assert_eq!(attr.kind, fuser::FileType::RegularFile);
+        let original = fs.lookup_entry(&italy, "rome.jpg".as_ref()).unwrap();
+        assert_ne!(original.ino, ino);
+        // the listing hands out the same inode
+        let listed = fs.dir_entries(&italy).unwrap();
+        assert!(listed
+            .iter()
+            .any(|(name, attr)| name == "rome.2.jpg" && attr.ino == ino));
+    }
+
+    #[test]
+    fn wildcards_dont_backtrack_exponentially() {
+        let name = "a".repeat(10_000);
+        assert!(!matches(&format!("{}b", "*a".repeat(100)), &name));
+        assert!(matches(&"*a".repeat(100), &name));
+    }
+}
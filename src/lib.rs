@@ -1,9 +1,65 @@
 pub mod filesystem;
 pub use filesystem::TagsFs;
 
+pub mod config;
+
+pub mod daemon;
+
 pub mod database;
 pub use database::TagsFsDb;
 
 pub mod error;
 
+pub mod export;
+
+pub mod lock;
+
+pub mod materialize;
+
+pub mod mount;
+
+pub mod tmsu;
+
+pub mod tree;
+
+pub mod xattr;
+
 pub type Tag = String;
+
+/// An empty directory for the files of the test `name`, removed when dropped
+#[cfg(test)]
+pub(crate) struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub(crate) fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("tagsfs-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path.canonicalize().unwrap())
+    }
+
+    /// Create the empty file `path` and its directories
+    pub(crate) fn touch(&self, path: &str) -> std::path::PathBuf {
+        let path = self.0.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "").unwrap();
+        path
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TestDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
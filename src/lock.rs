@@ -0,0 +1,369 @@
+use std::{collections::HashMap, mem};
+
+use libc::{c_int, EAGAIN, EINTR, EINVAL, F_RDLCK, F_UNLCK, F_WRLCK};
+use log::trace;
+
+use crate::error::{Error, Result};
+
+/// A byte range lock held by `owner` on `[start, end]` (both inclusive)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lock {
+    pub owner: u64,
+    pub start: u64,
+    pub end: u64,
+    pub typ: c_int,
+    pub pid: u32,
+}
+
+impl Lock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflicts(&self, other: &Lock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other.start, other.end)
+            && (self.typ == F_WRLCK || other.typ == F_WRLCK)
+    }
+}
+
+/// The answer to a parked `setlk` request
+pub trait Answer {
+    fn answer(self, result: Result<()>);
+}
+
+impl Answer for fuser::ReplyEmpty {
+    fn answer(self, result: Result<()>) {
+        match result {
+            Ok(()) => self.ok(),
+            Err(Error::StdC(errno)) => self.error(errno),
+            Err(_) => self.error(EINVAL),
+        }
+    }
+}
+
+/// A `setlk` request with `sleep` set that has to wait for a conflicting lock to go away
+struct Waiter<R> {
+    ino: u64,
+    lock: Lock,
+    reply: R,
+}
+
+/// In-process manager for POSIX (`fcntl`) locks keyed by inode and lock owner
+///
+/// The kernel only forwards lock requests to us, all the bookkeeping including blocking requests
+/// happens here. Since the filesystem is driven by a single thread blocking requests are not
+/// waited on, their reply is parked until a conflicting lock is released.
+pub struct LockManager<R = fuser::ReplyEmpty> {
+    locks: HashMap<u64, Vec<Lock>>,
+    waiters: Vec<Waiter<R>>,
+}
+
+impl<R> Default for LockManager<R> {
+    fn default() -> Self {
+        Self {
+            locks: HashMap::new(),
+            waiters: Vec::new(),
+        }
+    }
+}
+
+impl<R: Answer> LockManager<R> {
+    /// First lock on `ino` which would prevent `lock` from being acquired
+    pub fn conflicting(&self, ino: u64, lock: &Lock) -> Option<Lock> {
+        self.locks
+            .get(&ino)?
+            .iter()
+            .find(|held| held.conflicts(lock))
+            .copied()
+    }
+
+    /// Try to acquire (or release with `F_UNLCK`) `lock` on `ino`
+    pub fn set(&mut self, ino: u64, lock: Lock) -> Result<()> {
+        match lock.typ {
+            F_UNLCK => {
+                self.unlock(ino, lock.owner, lock.start, lock.end);
+                Ok(())
+            }
+            F_RDLCK | F_WRLCK => {
+                if self.conflicting(ino, &lock).is_some() {
+                    return Err(Error::StdC(EAGAIN));
+                }
+                // downgrading or shrinking a lock may let parked requests through
+                if self.acquire(ino, lock) {
+                    self.wake(ino);
+                }
+                Ok(())
+            }
+            _ => Err(Error::StdC(EINVAL)),
+        }
+    }
+
+    /// Acquire `lock` once it doesn't conflict anymore and answer `reply` then
+    pub fn wait(&mut self, ino: u64, lock: Lock, reply: R) {
+        trace!("lock {lock:?} on {ino} has to wait");
+        self.waiters.push(Waiter { ino, lock, reply });
+    }
+
+    /// Release everything `owner` holds on `ino` and give up its parked requests
+    pub fn release_owner(&mut self, ino: u64, owner: u64) {
+        // granting a parked request later would leave a lock behind nobody ever releases
+        let (gone, waiters): (Vec<_>, _) = mem::take(&mut self.waiters)
+            .into_iter()
+            .partition(|waiter| waiter.ino == ino && waiter.lock.owner == owner);
+        self.waiters = waiters;
+        for waiter in gone {
+            trace!("dropping lock {:?} on {ino} of released owner", waiter.lock);
+            waiter.reply.answer(Err(Error::StdC(EINTR)));
+        }
+        self.unlock(ino, owner, 0, u64::MAX);
+    }
+
+    fn unlock(&mut self, ino: u64, owner: u64, start: u64, end: u64) {
+        self.remove_range(ino, owner, start, end);
+        self.wake(ino);
+    }
+
+    /// Put the non-conflicting `lock` in place, whether it replaced a lock of its owner
+    fn acquire(&mut self, ino: u64, lock: Lock) -> bool {
+        // a new lock replaces whatever the owner held in the range before
+        let replaced = self.remove_range(ino, lock.owner, lock.start, lock.end);
+        let locks = self.locks.entry(ino).or_default();
+        locks.push(lock);
+        merge_adjacent(locks, lock.owner);
+        replaced
+    }
+
+    /// Release the range of `owner`'s locks, whether any lock was affected
+    fn remove_range(&mut self, ino: u64, owner: u64, start: u64, end: u64) -> bool {
+        let locks = match self.locks.get_mut(&ino) {
+            Some(locks) => locks,
+            None => return false,
+        };
+        let mut removed = false;
+        let mut remaining = Vec::with_capacity(locks.len());
+        for held in locks.drain(..) {
+            if held.owner != owner || !held.overlaps(start, end) {
+                remaining.push(held);
+                continue;
+            }
+            removed = true;
+            // keep the parts of the lock outside of the released range
+            if held.start < start {
+                remaining.push(Lock {
+                    end: start - 1,
+                    ..held
+                });
+            }
+            if held.end > end {
+                remaining.push(Lock {
+                    start: end + 1,
+                    ..held
+                });
+            }
+        }
+        if remaining.is_empty() {
+            self.locks.remove(&ino);
+        } else {
+            *locks = remaining;
+        }
+        removed
+    }
+
+    /// Grant every parked request on `ino` that can be granted now
+    fn wake(&mut self, ino: u64) {
+        // a granted request may downgrade a lock its owner held before and unblock others, so
+        // the scan starts over after every grant
+        while let Some(i) = self
+            .waiters
+            .iter()
+            .position(|waiter| waiter.ino == ino && self.conflicting(ino, &waiter.lock).is_none())
+        {
+            let Waiter { ino, lock, reply } = self.waiters.remove(i);
+            trace!("waking lock {lock:?} on {ino}");
+            self.acquire(ino, lock);
+            reply.answer(Ok(()));
+        }
+    }
+}
+
+/// Join overlapping or touching locks of the same type held by `owner`
+fn merge_adjacent(locks: &mut Vec<Lock>, owner: u64) {
+    locks.sort_by_key(|l| (l.owner, l.typ, l.start));
+    let mut merged: Vec<Lock> = Vec::with_capacity(locks.len());
+    for lock in locks.drain(..) {
+        match merged.last_mut() {
+            Some(last)
+                if last.owner == owner
+                    && lock.owner == owner
+                    && last.typ == lock.typ
+                    && last.end.saturating_add(1) >= lock.start =>
+            {
+                last.end = last.end.max(lock.end);
+            }
+            _ => merged.push(lock),
+        }
+    }
+    *locks = merged;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    /// Records the owners of the parked requests which were granted or refused
+    #[derive(Clone, Default)]
+    struct Answers {
+        granted: Rc<RefCell<Vec<u64>>>,
+        refused: Rc<RefCell<Vec<u64>>>,
+    }
+
+    /// A parked request of `owner`
+    struct Parked(Answers, u64);
+
+    impl Answer for Parked {
+        fn answer(self, result: Result<()>) {
+            match result {
+                Ok(()) => self.0.granted.borrow_mut().push(self.1),
+                Err(_) => self.0.refused.borrow_mut().push(self.1),
+            }
+        }
+    }
+
+    fn lock(owner: u64, start: u64, end: u64, typ: c_int) -> Lock {
+        Lock {
+            owner,
+            start,
+            end,
+            typ,
+            pid: owner as u32,
+        }
+    }
+
+    fn held(locks: &LockManager<Parked>, ino: u64) -> Vec<(u64, u64, u64, c_int)> {
+        let mut held: Vec<_> = locks
+            .locks
+            .get(&ino)
+            .into_iter()
+            .flatten()
+            .map(|l| (l.owner, l.start, l.end, l.typ))
+            .collect();
+        held.sort();
+        held
+    }
+
+    #[test]
+    fn conflicts() {
+        let mut locks = LockManager::<Parked>::default();
+        locks.set(1, lock(1, 0, 9, F_RDLCK)).unwrap();
+        locks.set(1, lock(2, 5, 14, F_RDLCK)).unwrap();
+        assert!(matches!(
+            locks.set(1, lock(3, 9, 9, F_WRLCK)),
+            Err(Error::StdC(EAGAIN))
+        ));
+        assert_eq!(locks.conflicting(1, &lock(3, 15, 20, F_WRLCK)), None);
+        assert_eq!(locks.conflicting(2, &lock(3, 0, 9, F_WRLCK)), None);
+        // an owner never conflicts with itself
+        assert_eq!(locks.conflicting(1, &lock(1, 0, 4, F_WRLCK)), None);
+        assert_eq!(
+            locks
+                .conflicting(1, &lock(3, 12, 12, F_WRLCK))
+                .map(|l| l.owner),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn merges_adjacent_locks_of_an_owner() {
+        let mut locks = LockManager::<Parked>::default();
+        locks.set(1, lock(1, 0, 4, F_RDLCK)).unwrap();
+        locks.set(1, lock(1, 5, 9, F_RDLCK)).unwrap();
+        locks.set(1, lock(1, 8, 20, F_RDLCK)).unwrap();
+        locks.set(1, lock(2, 21, 30, F_RDLCK)).unwrap();
+        locks.set(1, lock(1, 40, 50, F_WRLCK)).unwrap();
+        assert_eq!(
+            held(&locks, 1),
+            [
+                (1, 0, 20, F_RDLCK),
+                (1, 40, 50, F_WRLCK),
+                (2, 21, 30, F_RDLCK)
+            ]
+        );
+    }
+
+    #[test]
+    fn unlock_splits() {
+        let mut locks = LockManager::<Parked>::default();
+        locks.set(1, lock(1, 0, 99, F_WRLCK)).unwrap();
+        locks.set(1, lock(1, 10, 19, F_UNLCK)).unwrap();
+        assert_eq!(held(&locks, 1), [(1, 0, 9, F_WRLCK), (1, 20, 99, F_WRLCK)]);
+        // a lock in the middle of another one of a different type splits it as well
+        locks.set(1, lock(1, 50, 59, F_RDLCK)).unwrap();
+        assert_eq!(
+            held(&locks, 1),
+            [
+                (1, 0, 9, F_WRLCK),
+                (1, 20, 49, F_WRLCK),
+                (1, 50, 59, F_RDLCK),
+                (1, 60, 99, F_WRLCK)
+            ]
+        );
+        locks.release_owner(1, 1);
+        assert!(held(&locks, 1).is_empty());
+    }
+
+    #[test]
+    fn unlock_wakes_waiters() {
+        let answers = Answers::default();
+        let mut locks = LockManager::default();
+        locks.set(1, lock(1, 0, 9, F_WRLCK)).unwrap();
+        locks.wait(1, lock(2, 0, 9, F_WRLCK), Parked(answers.clone(), 2));
+        locks.wait(1, lock(3, 0, 9, F_WRLCK), Parked(answers.clone(), 3));
+        locks.release_owner(1, 1);
+        // the first waiter gets the lock and blocks the second
+        assert_eq!(*answers.granted.borrow(), [2]);
+        locks.release_owner(1, 2);
+        assert_eq!(*answers.granted.borrow(), [2, 3]);
+    }
+
+    #[test]
+    fn downgrade_wakes_readers() {
+        let answers = Answers::default();
+        let mut locks = LockManager::default();
+        locks.set(1, lock(1, 0, 9, F_WRLCK)).unwrap();
+        locks.wait(1, lock(2, 0, 9, F_RDLCK), Parked(answers.clone(), 2));
+        locks.wait(1, lock(3, 5, 5, F_RDLCK), Parked(answers.clone(), 3));
+        locks.wait(1, lock(4, 0, 0, F_WRLCK), Parked(answers.clone(), 4));
+        locks.set(1, lock(1, 0, 9, F_RDLCK)).unwrap();
+        assert_eq!(*answers.granted.borrow(), [2, 3]);
+        assert_eq!(held(&locks, 1).len(), 3);
+    }
+
+    #[test]
+    fn shrinking_wakes_waiters() {
+        let answers = Answers::default();
+        let mut locks = LockManager::default();
+        locks.set(1, lock(1, 0, 99, F_WRLCK)).unwrap();
+        locks.wait(1, lock(2, 50, 59, F_WRLCK), Parked(answers.clone(), 2));
+        locks.set(1, lock(1, 0, 9, F_WRLCK)).unwrap();
+        assert!(answers.granted.borrow().is_empty());
+        locks.set(1, lock(1, 10, 99, F_UNLCK)).unwrap();
+        assert_eq!(*answers.granted.borrow(), [2]);
+    }
+
+    #[test]
+    fn released_owners_stop_waiting() {
+        let answers = Answers::default();
+        let mut locks = LockManager::default();
+        locks.set(1, lock(1, 0, 9, F_WRLCK)).unwrap();
+        locks.wait(1, lock(2, 0, 9, F_WRLCK), Parked(answers.clone(), 2));
+        locks.release_owner(1, 2);
+        assert_eq!(*answers.refused.borrow(), [2]);
+        locks.set(1, lock(1, 0, 9, F_UNLCK)).unwrap();
+        assert!(answers.granted.borrow().is_empty());
+        locks.set(1, lock(3, 0, 9, F_WRLCK)).unwrap();
+        assert_eq!(held(&locks, 1), [(3, 0, 9, F_WRLCK)]);
+    }
+}
@@ -1,10 +1,29 @@
-use std::path::PathBuf;
+use std::{
+    collections::BTreeSet,
+    ffi::OsString,
+    fs::File,
+    os::unix::prelude::OsStrExt,
+    path::{Path, PathBuf},
+};
 
 use anyhow::anyhow;
-use clap::Parser;
-use tagsfs::TagsFs;
+use clap::{IntoApp, Parser, Subcommand};
+use itertools::Itertools as _;
+use rusqlite::ErrorCode;
+use tagsfs::{
+    config::{self, Config, Rules},
+    daemon::{Daemon, PidFile, Signals},
+    error::Error,
+    export::{Export, Format, Mode},
+    filesystem::{self, DEFAULT_IGNORE},
+    materialize::{Link, Materialize},
+    tmsu,
+    tree::{Transfer, TreeImport},
+    Tag, TagsFs, TagsFsDb,
+};
 
 #[derive(Parser)]
+#[clap(after_help = MOUNT_HELP)]
 /// Commandline option
 struct Options {
     #[clap()]
@@ -13,29 +32,891 @@ struct Options {
     #[clap(short, long)]
     /// where to mount the TagFS to.
     mountpoint: Option<PathBuf>,
+    #[clap(short, long)]
+    /// Directory with the files which are tagged
+    source: Option<PathBuf>,
+    #[clap(short, long)]
+    /// Config file to use instead of `$XDG_CONFIG_HOME/tagsfs/config.toml`
+    config: Option<PathBuf>,
     #[clap(short, long, parse(from_occurrences))]
     /// Verbosity of logging (specify multiple times for higher level)
     verbose: usize,
     #[clap(short, long)]
     /// Don't log anything
     quiet: bool,
+    #[clap(long, conflicts_with = "allow-root")]
+    /// Allow all users to access the mount (requires `user_allow_other` in /etc/fuse.conf)
+    allow_other: bool,
+    #[clap(long)]
+    /// Allow root to access the mount in addition to the mounting user
+    allow_root: bool,
+    #[clap(long)]
+    /// Let the kernel check permissions instead of tagsfs
+    default_permissions: bool,
+    #[clap(long)]
+    /// Unmount automatically when tagsfs exits, even if it is killed
+    auto_unmount: bool,
+    #[clap(long)]
+    /// Mount read-only and never write to the database, which may be on read-only media
+    read_only: bool,
+    #[clap(short = 'o', long = "options", multiple_occurrences(true))]
+    /// Further mount options separated by commas, as for `mount -o`
+    mount_options: Vec<String>,
+    #[clap(short, long)]
+    /// Stay in the foreground instead of running in the background once mounted
+    foreground: bool,
+    #[clap(long)]
+    /// Write the process id to this file while mounted
+    pidfile: Option<PathBuf>,
+    #[clap(long)]
+    /// Import tags from the `user.xdg.tags` attribute of the files before mounting
+    import_xdg_tags: bool,
+    #[clap(long)]
+    /// Write tag changes back to the `user.xdg.tags` attribute of the files
+    sync_xdg_tags: bool,
+    #[clap(long, conflicts_with = "sync-xdg-tags")]
+    /// Don't sync with `user.xdg.tags`, even if the config file or the database say so
+    no_sync_xdg_tags: bool,
+    #[clap(long)]
+    /// Let rmdir remove the tag from the files in a non-empty tag directory instead of failing
+    rmdir_untags: bool,
+    #[clap(long, conflicts_with = "rmdir-untags")]
+    /// Let rmdir of a non-empty tag directory fail, even if the config file or the database
+    /// say otherwise
+    no_rmdir_untags: bool,
+    #[clap(long, parse(try_from_str = config::parse_retention_days))]
+    /// Permanently delete files which are in the trash for longer than this many days
+    trash_retention_days: Option<u64>,
+    #[clap(long, multiple_occurrences(true))]
+    /// Additional file name pattern (`*` and `?` wildcards) of temporary files which are never
+    /// tagged
+    ignore: Vec<String>,
+    #[clap(long)]
+    /// Don't ignore the temporary files of common editors by default
+    no_default_ignore: bool,
+    #[clap(subcommand)]
+    /// Work on the database directly instead of mounting it
+    command: Option<Command>,
 }
 
-fn main() -> anyhow::Result<()> {
-    let opt = Options::parse();
+#[derive(Subcommand)]
+/// Tag management without a mount, safe to use while the database is mounted
+enum Command {
+    /// Add tags to a file
+    Tag {
+        file: PathBuf,
+        #[clap(required = true, parse(try_from_str = parse_tag))]
+        tags: Vec<Tag>,
+    },
+    /// Remove tags from a file
+    Untag {
+        file: PathBuf,
+        #[clap(required = true, parse(try_from_str = parse_tag))]
+        tags: Vec<Tag>,
+    },
+    /// List the tags of a file
+    Tags { file: PathBuf },
+    /// List the files which carry all of the given tags
+    Files {
+        #[clap(required = true)]
+        tags: Vec<Tag>,
+    },
+    /// Rename a tag, merging it into an existing tag of the new name
+    MvTag {
+        #[clap(parse(try_from_str = parse_tag))]
+        from: Tag,
+        #[clap(parse(try_from_str = parse_tag))]
+        to: Tag,
+    },
+    /// Delete tags from all files
+    RmTag {
+        #[clap(required = true)]
+        tags: Vec<Tag>,
+    },
+    /// List all tags
+    LsTags {
+        #[clap(long)]
+        /// Show the number of files carrying each tag
+        counts: bool,
+    },
+    /// Show or change the settings stored in the database
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Write all files, tags and settings to stdout or a file
+    Export {
+        #[clap(long, default_value = "json", possible_values = ["json", "csv"])]
+        format: Format,
+        #[clap(short, long)]
+        /// File to write to instead of stdout
+        output: Option<PathBuf>,
+    },
+    /// Create a directory tree of links to the files of a tag directory, re-running it only
+    /// applies the changes
+    Materialize {
+        /// Tags of the directory, separated by `/` like its path in the mount
+        query: String,
+        dest: PathBuf,
+        #[clap(long)]
+        /// Create hard links instead of symlinks
+        hardlink: bool,
+        #[clap(long, default_value = "1")]
+        /// Levels of tag directories to create below the files of the query
+        depth: usize,
+    },
+    /// Import tags from another tagging tool or an export
+    Import {
+        #[clap(subcommand)]
+        command: ImportCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print the value of a setting
+    Get { key: String },
+    /// Store a setting, lists are separated by commas
+    Set { key: String, value: String },
+    /// Remove a setting
+    Unset { key: String },
+    /// Print all stored settings
+    List,
+}
+
+#[derive(Subcommand)]
+enum ImportCommand {
+    /// Import the tags of a TMSU database, usually `.tmsu/db`
+    Tmsu {
+        database: PathBuf,
+        #[clap(long)]
+        /// Directory the TMSU database stores its paths against, it corresponds to the source
+        /// directory [default: the directory containing `.tmsu`]
+        root: Option<PathBuf>,
+        #[clap(long)]
+        /// Only report what would be imported
+        dry_run: bool,
+    },
+    /// Move the files of a directory tree into the source directory, tagged with the names of
+    /// the directories they are in
+    Tree {
+        root: PathBuf,
+        #[clap(long)]
+        /// Leave the files in place and symlink them into the source directory
+        symlink: bool,
+        #[clap(long, multiple_occurrences(true))]
+        /// Pattern (`*` and `?` wildcards) of directory names which don't become tags
+        skip: Vec<String>,
+        #[clap(long, multiple_occurrences(true), parse(try_from_str = parse_rename))]
+        /// Use another tag for a directory name, given as `name=tag`, an empty tag skips it
+        rename: Vec<(String, Tag)>,
+        #[clap(long)]
+        /// Only print what would be imported
+        dry_run: bool,
+    },
+    /// Import what `export` wrote, `-` reads stdin
+    Export {
+        file: PathBuf,
+        #[clap(long, default_value = "json", possible_values = ["json", "csv"])]
+        format: Format,
+        #[clap(long)]
+        /// Remove all tags and settings first instead of merging
+        replace: bool,
+    },
+}
+
+impl Command {
+    fn run(self, db: &TagsFsDb, config: &Config) -> anyhow::Result<()> {
+        if self.writes() && db.is_read_only() {
+            return Err(anyhow!("the database is opened read-only"));
+        }
+        match self {
+            Command::Tag { file, tags } => {
+                let file = file_name(&file)?;
+                let current = db.file_tags(&file)?;
+                let tags: BTreeSet<_> = tags.into_iter().collect();
+                db.transaction(|db| db.add_tags_to_file(tags.difference(&current), &file))?;
+            }
+            Command::Untag { file, tags } => {
+                let file = file_name(&file)?;
+                db.transaction(|db| db.remove_tags_from_file(&tags, &file))?;
+            }
+            Command::Tags { file } => {
+                for tag in db.file_tags(file_name(&file)?)? {
+                    println!("{tag}");
+                }
+            }
+            Command::Files { tags } => {
+                for file in db.files_with_tags(&tags.into_iter().collect())?.iter().sorted() {
+                    println!("{file}");
+                }
+            }
+            Command::MvTag { from, to } => {
+                db.tag_id(&from)
+                    .map_err(|_| anyhow!("no tag named {from:?}"))?;
+                db.transaction(|db| db.rename_tag(&from, &to))?;
+            }
+            Command::RmTag { tags } => {
+                let tags: BTreeSet<_> = tags.into_iter().collect();
+                db.transaction(|db| db.delete_tags(&tags))?;
+            }
+            Command::LsTags { counts } => {
+                for (tag, count) in db.tag_counts()? {
+                    if counts {
+                        println!("{count}\t{tag}");
+                    } else {
+                        println!("{tag}");
+                    }
+                }
+            }
+            Command::Config { command } => command.run(db)?,
+            Command::Export { format, output } => {
+                let export = Export::from_db(db)?;
+                match output {
+                    Some(output) => export.write(File::create(output)?, format)?,
+                    None => export.write(std::io::stdout().lock(), format)?,
+                }
+            }
+            Command::Materialize {
+                query,
+                dest,
+                hardlink,
+                depth,
+            } => {
+                let source = config
+                    .source
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("no source directory specified"))?;
+                let query = query
+                    .split('/')
+                    .filter(|tag| !tag.is_empty())
+                    .map(String::from)
+                    .collect();
+                let materialize = Materialize {
+                    link: if hardlink {
+                        Link::Hardlink
+                    } else {
+                        Link::Symlink
+                    },
+                    depth,
+                };
+                let changes = materialize.run(db, source, &query, &dest)?;
+                println!(
+                    "added {}, removed {}, kept {} links",
+                    changes.added, changes.removed, changes.unchanged
+                );
+            }
+            Command::Import { command } => command.run(db, config)?,
+        }
+        Ok(())
+    }
+
+    /// Whether the command changes the database, which a read-only database must not
+    fn writes(&self) -> bool {
+        match self {
+            Command::Tag { .. } | Command::Untag { .. } => true,
+            Command::MvTag { .. } | Command::RmTag { .. } => true,
+            Command::Config { command } => {
+                matches!(
+                    command,
+                    ConfigCommand::Set { .. } | ConfigCommand::Unset { .. }
+                )
+            }
+            Command::Import { command } => match command {
+                ImportCommand::Tmsu { dry_run, .. } | ImportCommand::Tree { dry_run, .. } => {
+                    !dry_run
+                }
+                ImportCommand::Export { .. } => true,
+            },
+            _ => false,
+        }
+    }
+}
+
+impl ConfigCommand {
+    fn run(self, db: &TagsFsDb) -> anyhow::Result<()> {
+        let known = |key: &str| {
+            if config::KEYS.contains(&key) {
+                Ok(())
+            } else {
+                Err(anyhow!(
+                    "unknown setting {key:?}, known are {}",
+                    config::KEYS.join(", ")
+                ))
+            }
+        };
+        match self {
+            ConfigCommand::Get { key } => {
+                known(&key)?;
+                let value = db.setting(&key)?.ok_or_else(|| anyhow!("{key} isn't set"))?;
+                println!("{value}");
+            }
+            ConfigCommand::Set { key, value } => {
+                known(&key)?;
+                // refuse values which would keep the database from being mounted
+                Config::default().set(&key, &value)?;
+                db.set_setting(&key, &value)?;
+            }
+            ConfigCommand::Unset { key } => {
+                if !db.remove_setting(&key)? {
+                    return Err(anyhow!("{key} isn't set"));
+                }
+            }
+            ConfigCommand::List => {
+                for (key, value) in db.settings()? {
+                    println!("{key} = {value}");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A setting given by a pair of `--x` and `--no-x` flags, `None` if neither was passed
+fn switch(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+/// Files are known by their name in the source directory, so a path to one works as well
+fn file_name(file: &Path) -> anyhow::Result<String> {
+    Ok(file
+        .file_name()
+        .ok_or_else(|| anyhow!("{file:?} isn't a file"))?
+        .to_string_lossy()
+        .to_string())
+}
+
+impl Options {
+    /// The settings given on the command line, these take precedence over all others
+    fn overrides(&self) -> Config {
+        let mut mount_options = Vec::new();
+        for (set, option) in [
+            (self.allow_other, "allow_other"),
+            (self.allow_root, "allow_root"),
+            (self.default_permissions, "default_permissions"),
+            (self.auto_unmount, "auto_unmount"),
+            (self.read_only, "ro"),
+        ] {
+            if set {
+                mount_options.push(option.to_string());
+            }
+        }
+        for options in &self.mount_options {
+            mount_options.extend(
+                options
+                    .split(',')
+                    .filter(|option| !option.is_empty())
+                    .map(String::from),
+            );
+        }
+        let ignore = if self.no_default_ignore || !self.ignore.is_empty() {
+            let defaults = DEFAULT_IGNORE.iter().map(|p| p.to_string());
+            let defaults = defaults.filter(|_| !self.no_default_ignore);
+            Some(defaults.chain(self.ignore.iter().cloned()).collect())
+        } else {
+            None
+        };
+        Config {
+            source: self.source.clone(),
+            mountpoint: self.mountpoint.clone(),
+            mount_options,
+            log_level: (self.verbose > 0).then_some(self.verbose),
+            rules: Rules {
+                sync_xdg_tags: switch(self.sync_xdg_tags, self.no_sync_xdg_tags),
+                rmdir_untags: switch(self.rmdir_untags, self.no_rmdir_untags),
+                trash_retention_days: self.trash_retention_days,
+                ignore,
+            },
+        }
+    }
+
+    /// The config file given with `--config` or the one at the default location if it exists
+    fn config_file(&self) -> anyhow::Result<Config> {
+        let path = match &self.config {
+            Some(path) => path.clone(),
+            None => match Config::default_path().filter(|path| path.exists()) {
+                Some(path) => path,
+                None => return Ok(Config::default()),
+            },
+        };
+        Config::load(&path).map_err(|err| anyhow!("couldn't load config {path:?}: {err}"))
+    }
+
+    /// Open the database and resolve the settings
+    ///
+    /// The stored settings are read without writing to the database, so a read-only database
+    /// isn't created or migrated either.
+    fn open(&self) -> anyhow::Result<(TagsFsDb, Config)> {
+        let config = self.overrides().or(self.config_file()?);
+        if !self.database.exists() {
+            // a new database has no settings yet
+            return Ok((open_db(&self.database)?, config));
+        }
+        let stored = TagsFsDb::open_read_only(&self.database)?;
+        let config = config.or(Config::from_db(&stored)?);
+        if config.read_only() {
+            Ok((stored, config))
+        } else {
+            Ok((open_db(&self.database)?, config))
+        }
+    }
+
+    /// Resolve the settings again, the database is opened anew since the mount owns its own
+    fn reload(&self) -> anyhow::Result<Config> {
+        let db = TagsFsDb::open_read_only(&self.database)?;
+        Ok(self.overrides().or(self.config_file()?).or(Config::from_db(&db)?))
+    }
+}
+
+impl ImportCommand {
+    fn run(self, db: &TagsFsDb, config: &Config) -> anyhow::Result<()> {
+        match self {
+            ImportCommand::Tmsu {
+                database,
+                root,
+                dry_run,
+            } => {
+                let source = config
+                    .source
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("no source directory specified"))?;
+                let report = tmsu::import(db, &database, root.as_deref(), source, dry_run)?;
+                for unmappable in &report.unmappable {
+                    println!("skipped {}: {}", unmappable.entry, unmappable.reason);
+                }
+                println!(
+                    "{} {} tags to {} files, using {} distinct tags",
+                    if dry_run { "would add" } else { "added" },
+                    report.taggings,
+                    report.files,
+                    report.tags.len(),
+                );
+            }
+            ImportCommand::Tree {
+                root,
+                symlink,
+                skip,
+                rename,
+                dry_run,
+            } => {
+                let source = config
+                    .source
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("no source directory specified"))?;
+                let import = TreeImport {
+                    transfer: if symlink {
+                        Transfer::Symlink
+                    } else {
+                        Transfer::Move
+                    },
+                    skip,
+                    rename: rename.into_iter().collect(),
+                };
+                let planned = import.plan(&root, source)?;
+                for file in &planned {
+                    let renamed = if file.renamed() {
+                        format!(" as {}", file.name)
+                    } else {
+                        String::new()
+                    };
+                    println!(
+                        "{}{renamed}: {}",
+                        file.from.display(),
+                        file.tags.iter().join(", ")
+                    );
+                }
+                if !dry_run {
+                    import.apply(db, &planned, source)?;
+                }
+            }
+            ImportCommand::Export {
+                file,
+                format,
+                replace,
+            } => import(db, &file, format, replace)?,
+        }
+        Ok(())
+    }
+}
+
+fn import(db: &TagsFsDb, file: &Path, format: Format, replace: bool) -> anyhow::Result<()> {
+    let export = if file == Path::new("-") {
+        Export::read(std::io::stdin().lock(), format)?
+    } else {
+        Export::read(File::open(file)?, format)?
+    };
+    let mode = if replace { Mode::Replace } else { Mode::Merge };
+    export.apply(db, mode)?;
+    Ok(())
+}
+
+/// A tag which works as a directory name, like the ones `mkdir` creates
+fn parse_tag(tag: &str) -> anyhow::Result<Tag> {
+    if !filesystem::is_valid_tag(tag) {
+        return Err(anyhow!(
+            "tags can't be empty, `.` or `..`, contain '/' or be longer than a file name"
+        ));
+    }
+    Ok(tag.to_string())
+}
+
+fn parse_rename(rename: &str) -> anyhow::Result<(String, Tag)> {
+    let (name, tag) = rename
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected `name=tag`, got {rename:?}"))?;
+    if tag.contains('/') {
+        return Err(anyhow!("tags can't contain '/'"));
+    }
+    Ok((name.to_string(), tag.to_string()))
+}
+
+/// Options `mount` and fstab use which don't mean anything to a FUSE file system
+const IGNORED_MOUNT_OPTIONS: &[&str] = &[
+    "defaults", "auto", "noauto", "user", "nouser", "users", "owner", "group", "nofail", "_netdev",
+];
+
+/// How to mount with `mount` and from fstab, shown below the options of `--help`
+const MOUNT_HELP: &str = "\
+MOUNTING WITH mount AND FSTAB:
+    Link the binary as mount helper to use the file system type `tagsfs`:
+        ln -s \"$(command -v tagsfs)\" /sbin/mount.tagsfs
+        mount -t tagsfs -o source=/home/me/library tags.sqlite /mnt/tags
+    The type `fuse.tagsfs` works without the link, as long as tagsfs is in $PATH:
+        /home/me/tags.sqlite /mnt/tags fuse.tagsfs source=/home/me/library,noauto,user 0 0";
+
+/// Whether tagsfs was called like a mount helper, see [`mount_helper_args`]
+///
+/// `mount` runs the helper as `mount.tagsfs` (or `mount.fuse.tagsfs`) while `mount.fuse` runs
+/// `tagsfs <database> <mountpoint> -o ...` for the type `fuse.tagsfs`. The regular command line
+/// never has a second positional argument other than a subcommand.
+fn is_mount_helper(args: &[OsString]) -> bool {
+    let named_helper = args
+        .first()
+        .and_then(|argv0| Path::new(argv0).file_name())
+        .is_some_and(|name| name.as_bytes().starts_with(b"mount."));
+    let positional = |i: usize| args.get(i).filter(|arg| !arg.as_bytes().starts_with(b"-"));
+    let is_subcommand = |arg: &OsString| {
+        arg == "help"
+            || Options::command()
+                .get_subcommands()
+                .any(|command| arg == command.get_name())
+    };
+    named_helper
+        || matches!((positional(1), positional(2)), (Some(_), Some(arg)) if !is_subcommand(arg))
+}
+
+/// Translate `mount.tagsfs <database> <mountpoint> [-sfnv] [-o options]` into the regular
+/// command line
+///
+/// The tagsfs specific options `db=` and `source=` set the database, which replaces the first
+/// argument, and the source directory. All other options are mount options.
+fn mount_helper_args(args: Vec<OsString>) -> anyhow::Result<Vec<OsString>> {
+    let mut args = args.into_iter();
+    let argv0 = args.next().unwrap_or_else(|| "tagsfs".into());
+    let mut positional = Vec::new();
+    let mut options = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("-o") => {
+                let value = args.next().ok_or_else(|| anyhow!("-o needs a value"))?;
+                options.push(value.to_string_lossy().to_string());
+            }
+            Some(arg) if arg.starts_with("-o") => options.push(arg[2..].to_string()),
+            // the file system type and the mount namespace
+            Some("-t" | "-N") => {
+                args.next();
+            }
+            // sloppy, fake, don't write mtab and verbose
+            Some(arg) if arg.starts_with('-') => {}
+            _ => positional.push(arg),
+        }
+    }
+    let (mut database, mountpoint) = match <[OsString; 2]>::try_from(positional) {
+        Ok([database, mountpoint]) => (database, mountpoint),
+        Err(_) => {
+            return Err(anyhow!(
+                "usage: {} <database> <mountpoint> [-o options]",
+                argv0.to_string_lossy()
+            ))
+        }
+    };
+    let mut source = None;
+    let mut mount_options = Vec::new();
+    for option in options.iter().flat_map(|options| options.split(',')) {
+        match option.split_once('=') {
+            Some(("db", db)) => database = db.into(),
+            Some(("source", dir)) => source = Some(dir),
+            Some(("comment", _)) => {}
+            _ if option.is_empty()
+                || option.starts_with("x-")
+                || IGNORED_MOUNT_OPTIONS.contains(&option) => {}
+            _ => mount_options.push(option),
+        }
+    }
+    let mut args = vec![argv0, database, "--mountpoint".into(), mountpoint];
+    if let Some(source) = source {
+        args.extend(["--source".into(), source.into()]);
+    }
+    if !mount_options.is_empty() {
+        args.extend(["-o".into(), mount_options.join(",").into()]);
+    }
+    Ok(args)
+}
+
+/// Open the database at `path`, read-only if it can't be written to, e.g. on read-only media
+fn open_db(path: &Path) -> anyhow::Result<TagsFsDb> {
+    match TagsFsDb::new(path) {
+        Err(Error::Database(rusqlite::Error::SqliteFailure(err, _)))
+            if matches!(err.code, ErrorCode::ReadOnly | ErrorCode::CannotOpen) =>
+        {
+            Ok(TagsFsDb::open_read_only(path)?)
+        }
+        db => Ok(db?),
+    }
+}
+
+fn init_logging(quiet: bool, config: &Config) {
     stderrlog::new()
         .module(module_path!())
-        .quiet(opt.quiet)
-        .verbosity(opt.verbose)
+        .quiet(quiet)
+        .verbosity(config.log_level.unwrap_or(0))
         .init()
         .unwrap();
-    let fs = TagsFs::new(opt.database, Some("tryout/files".into()))?;
-    let mountpoint = opt
+}
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let mut opt = if is_mount_helper(&args) {
+        Options::parse_from(mount_helper_args(args)?)
+    } else {
+        Options::parse_from(args)
+    };
+    if let Some(command) = opt.command.take() {
+        let (db, config) = opt.open()?;
+        init_logging(opt.quiet, &config);
+        return command.run(&db, &config);
+    }
+    // the daemon runs in `/`, paths needed for reloading have to keep working there, the database
+    // is only created after the fork
+    opt.database = std::env::current_dir()?.join(&opt.database);
+    if let Some(config) = &mut opt.config {
+        *config = config.canonicalize()?;
+    }
+    if let Some(pidfile) = &mut opt.pidfile {
+        *pidfile = std::env::current_dir()?.join(&pidfile);
+    }
+
+    // an SQLite connection doesn't survive a fork, the database is only opened in the daemon
+    let mut daemon = if opt.foreground {
+        None
+    } else {
+        Some(Daemon::fork()?)
+    };
+    let mounted = mount(&opt, &mut daemon);
+    if let (Err(err), Some(daemon)) = (&mounted, daemon) {
+        // the process in the foreground reports the error and exits
+        daemon.fail(format!("{err:?}"))?;
+        std::process::exit(1);
+    }
+    mounted
+}
+
+/// Mount the database of `opt` and serve it until a signal or an unmount ends it
+///
+/// `daemon` is told once the file system is mounted.
+fn mount(opt: &Options, daemon: &mut Option<Daemon>) -> anyhow::Result<()> {
+    let (db, config) = opt.open()?;
+    init_logging(opt.quiet, &config);
+    let source = config
+        .source
+        .as_ref()
+        .ok_or_else(|| anyhow!("no source directory specified"))?
+        .canonicalize()?;
+    let mountpoint = config
         .mountpoint
-        .ok_or_else(|| anyhow!("no mountpoint specified"))
-        .or_else(|_| fs.db.mountpoint())
-        ?;
-    // fuser::mount2(fs, mountpoint, &[MountOption::AllowRoot, MountOption::AutoUnmount])?;
-    fuser::mount2(fs, mountpoint, &[])?;
+        .as_ref()
+        .ok_or_else(|| anyhow!("no mountpoint specified"))?
+        .canonicalize()?;
+
+    let mut fs = TagsFs::from_db(db, source);
+    config.rules.apply(&mut fs);
+    if opt.import_xdg_tags && fs.read_only {
+        return Err(anyhow!("can't import desktop tags into a read-only mount"));
+    }
+    if opt.import_xdg_tags {
+        let imported = fs.import_xdg_tags()?;
+        log::info!("imported desktop tags of {imported} files");
+    }
+    if let Some(retention) = fs.trash_retention.filter(|_| !fs.read_only) {
+        fs.purge_trash(retention)?;
+    }
+
+    let _pidfile = opt.pidfile.as_ref().map(PidFile::create).transpose()?;
+    // an unmount from outside ends the session, wake up the signal loop below to exit as well
+    fs.on_destroy(|| unsafe {
+        libc::kill(libc::getpid(), libc::SIGTERM);
+    });
+    let rules = fs.rules_handle();
+    let signals = Signals::block(&[libc::SIGINT, libc::SIGTERM, libc::SIGHUP])?;
+    let session = fuser::Session::new(fs, &mountpoint, &config.mount_options())?.spawn()?;
+    if let Some(daemon) = daemon.take() {
+        daemon.ready()?;
+    }
+    loop {
+        match signals.wait()? {
+            libc::SIGHUP => match opt.reload() {
+                Ok(config) => rules.replace(config.rules),
+                Err(err) => log::warn!("couldn't reload the config: {err}"),
+            },
+            signal => {
+                log::info!("unmounting {mountpoint:?} after signal {signal}");
+                break;
+            }
+        }
+    }
+    // dropping the session unmounts
+    drop(session);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn command_line_is_consistent() {
+        Options::command().debug_assert();
+    }
+
+    #[test]
+    fn flags_override_settings_both_ways() {
+        let overrides = |args: &[&str]| {
+            let options = Options::try_parse_from(["tagsfs", "db"].iter().chain(args));
+            options.unwrap().overrides().rules
+        };
+        assert_eq!(overrides(&[]).sync_xdg_tags, None);
+        assert_eq!(overrides(&["--sync-xdg-tags"]).sync_xdg_tags, Some(true));
+        assert_eq!(
+            overrides(&["--no-sync-xdg-tags"]).sync_xdg_tags,
+            Some(false)
+        );
+        assert_eq!(overrides(&["--no-rmdir-untags"]).rmdir_untags, Some(false));
+        assert!(
+            Options::try_parse_from(["tagsfs", "db", "--rmdir-untags", "--no-rmdir-untags"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_tags() {
+        let parses =
+            |args: &[&str]| Options::try_parse_from(["tagsfs", "db"].iter().chain(args)).is_ok();
+        assert!(parses(&["tag", "f.jpg", "italy", "rome"]));
+        assert!(!parses(&["tag", "f.jpg", "a/b"]));
+        assert!(!parses(&["tag", "f.jpg", "italy", ""]));
+        assert!(!parses(&["untag", "f.jpg", ".."]));
+        assert!(!parses(&["mv-tag", "italy", ""]));
+        assert!(!parses(&["mv-tag", "a/b", "italy"]));
+        assert!(parses(&["mv-tag", "italy", "rome"]));
+    }
+
+    #[test]
+    fn read_only_runs_leave_the_database_untouched() {
+        let dir = std::env::temp_dir().join(format!("tagsfs-read-only-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("tags.sqlite");
+        // the layout before tagsfs added its own tables and moved `config` to `settings`
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE tags (id INTEGER PRIMARY KEY, tag TEXT NOT NULL UNIQUE); \
+                 CREATE TABLE file_tags (file TEXT NOT NULL, tag_id INTEGER NOT NULL); \
+                 CREATE TABLE inodes (\
+                     id INTEGER PRIMARY KEY, discriminant TEXT NOT NULL, data TEXT NOT NULL\
+                 ); \
+                 CREATE TABLE config (key TEXT, value TEXT); \
+                 INSERT INTO tags VALUES (1, 'italy'); \
+                 INSERT INTO file_tags VALUES ('rome.jpg', 1); \
+                 INSERT INTO config VALUES ('source', '/library');",
+            )
+            .unwrap();
+        let before = std::fs::read(&path).unwrap();
+
+        let run = |args: &[&str]| {
+            let mut opt = Options::try_parse_from(
+                ["tagsfs", path.to_str().unwrap(), "--read-only"]
+                    .iter()
+                    .chain(args),
+            )
+            .unwrap();
+            let (db, config) = opt.open().unwrap();
+            assert!(db.is_read_only());
+            assert_eq!(config.source.as_deref(), Some(Path::new("/library")));
+            opt.command.take().unwrap().run(&db, &config)
+        };
+        assert!(run(&["ls-tags"]).is_ok());
+        assert!(run(&["tag", "rome.jpg", "rome"]).is_err());
+        assert!(run(&["config", "set", "source", "/other"]).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn detects_mount_helper_calls() {
+        let helper = |call: &[&str]| is_mount_helper(&args(call));
+        assert!(helper(&["/sbin/mount.tagsfs", "db", "/mnt"]));
+        assert!(helper(&["tagsfs", "db", "/mnt", "-o", "rw"]));
+        assert!(!helper(&["tagsfs", "db", "-m", "/mnt"]));
+        assert!(!helper(&["tagsfs", "db", "tags", "file"]));
+        assert!(!helper(&["tagsfs", "db", "help"]));
+        assert!(!helper(&["tagsfs", "-v", "db"]));
+    }
+
+    #[test]
+    fn translates_mount_helper_arguments() {
+        let translated = mount_helper_args(args(&[
+            "mount.tagsfs",
+            "db",
+            "/mnt",
+            "-n",
+            "-s",
+            "-o",
+            "rw,allow_other,source=/library",
+            "-onoauto,x-systemd.automount,comment=x,,ro",
+            "-t",
+            "tagsfs",
+        ]))
+        .unwrap();
+        let expected = args(&[
+            "mount.tagsfs",
+            "db",
+            "--mountpoint",
+            "/mnt",
+            "--source",
+            "/library",
+            "-o",
+            "rw,allow_other,ro",
+        ]);
+        assert_eq!(translated, expected);
+    }
+
+    #[test]
+    fn mount_helper_options_replace_the_database() {
+        let translated =
+            mount_helper_args(args(&["tagsfs", "none", "/mnt", "-o", "db=tags.sqlite"]));
+        assert_eq!(
+            translated.unwrap(),
+            args(&["tagsfs", "tags.sqlite", "--mountpoint", "/mnt"])
+        );
+        assert!(mount_helper_args(args(&["tagsfs", "db"])).is_err());
+        assert!(mount_helper_args(args(&["tagsfs", "db", "/mnt", "-o"])).is_err());
+    }
+}
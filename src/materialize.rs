@@ -0,0 +1,308 @@
+//! Materialize a tag directory as a real directory tree of links into `source`
+//!
+//! The tree mirrors the mount: the files of the query at the top and a directory per further tag
+//! of those files, down to the chosen depth. Only tags which select any file get a directory. The
+//! links which were created are listed in [`MANIFEST`] in the destination, so a re-run only
+//! applies the differences and never touches anything else in the destination.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    os::unix::prelude::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+
+use crate::{error::Result, Tag, TagsFsDb};
+
+/// File in the destination with the paths of all links made by the last run
+pub const MANIFEST: &str = ".tagsfs-materialized";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Link {
+    Symlink,
+    /// Hard links need the destination on the file system of `source`
+    Hardlink,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Materialize {
+    pub link: Link,
+    /// How many levels of tag directories to create, `0` only links the files of the query
+    pub depth: usize,
+}
+
+/// What a run changed in the destination
+#[derive(Debug, Default)]
+pub struct Changes {
+    pub added: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+}
+
+impl Materialize {
+    /// Make `dest` show the files of the tag directory `query`
+    pub fn run(
+        &self,
+        db: &TagsFsDb,
+        source: &Path,
+        query: &BTreeSet<Tag>,
+        dest: &Path,
+    ) -> Result<Changes> {
+        let source = source.canonicalize()?;
+        let mut wanted = BTreeMap::new();
+        collect(db, &source, query, PathBuf::new(), self.depth, &mut wanted)?;
+
+        fs::create_dir_all(dest)?;
+        let manifest = dest.join(MANIFEST);
+        let previous: BTreeSet<PathBuf> = match fs::read_to_string(&manifest) {
+            Ok(manifest) => manifest.lines().map(PathBuf::from).collect(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut changes = Changes::default();
+        let mut made = BTreeSet::new();
+        for (relative, target) in &wanted {
+            let path = dest.join(relative);
+            if self.is_link_to(&path, target) {
+                changes.unchanged += 1;
+                made.insert(relative.clone());
+                continue;
+            }
+            if path.symlink_metadata().is_ok() {
+                if !previous.contains(relative) {
+                    warn!("{path:?} exists and wasn't made by tagsfs, leaving it alone");
+                    continue;
+                }
+                fs::remove_file(&path)?;
+            }
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            match self.link {
+                Link::Symlink => std::os::unix::fs::symlink(target, &path)?,
+                Link::Hardlink => fs::hard_link(target.canonicalize()?, &path)?,
+            }
+            changes.added += 1;
+            made.insert(relative.clone());
+        }
+
+        for relative in previous.difference(&made) {
+            if wanted.contains_key(relative) {
+                // left alone above since something else is in its place
+                continue;
+            }
+            match fs::remove_file(dest.join(relative)) {
+                Ok(()) => changes.removed += 1,
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+            remove_empty_parents(dest, relative);
+        }
+
+        let manifest_contents: String = made
+            .iter()
+            .map(|relative| format!("{}\n", relative.display()))
+            .collect();
+        fs::write(manifest, manifest_contents)?;
+        Ok(changes)
+    }
+
+    fn is_link_to(&self, path: &Path, target: &Path) -> bool {
+        match self.link {
+            Link::Symlink => fs::read_link(path).is_ok_and(|link| link == target),
+            Link::Hardlink => match (path.symlink_metadata(), target.metadata()) {
+                (Ok(link), Ok(target)) => link.dev() == target.dev() && link.ino() == target.ino(),
+                _ => false,
+            },
+        }
+    }
+}
+
+/// Add the links of the tag directory `tags` below `prefix` to `wanted`
+fn collect(
+    db: &TagsFsDb,
+    source: &Path,
+    tags: &BTreeSet<Tag>,
+    prefix: PathBuf,
+    depth: usize,
+    wanted: &mut BTreeMap<PathBuf, PathBuf>,
+) -> Result<()> {
+    let files = if tags.is_empty() {
+        let mut files = BTreeSet::new();
+        for file in fs::read_dir(source)? {
+            let file = file?;
+            if file.path().is_file() {
+                files.insert(file.file_name().to_string_lossy().to_string());
+            }
+        }
+        files
+    } else {
+        db.files_with_tags(tags)?
+            .into_iter()
+            .filter(|file| source.join(file).is_file())
+            .collect()
+    };
+    let mut sub_tags = BTreeSet::new();
+    for file in &files {
+        wanted.insert(prefix.join(file), source.join(file));
+        if depth > 0 {
+            sub_tags.extend(db.file_tags(file)?);
+        }
+    }
+    for tag in sub_tags.difference(tags) {
+        // a file hides a tag of the same name, just like in the mount
+        if files.contains(tag) {
+            continue;
+        }
+        let mut sub_tags = tags.clone();
+        sub_tags.insert(tag.clone());
+        collect(db, source, &sub_tags, prefix.join(tag), depth - 1, wanted)?;
+    }
+    Ok(())
+}
+
+/// Remove the directories of `relative` below `dest` which are empty now
+fn remove_empty_parents(dest: &Path, relative: &Path) {
+    for parent in relative.ancestors().skip(1) {
+        if parent.as_os_str().is_empty() || fs::remove_dir(dest.join(parent)).is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestDir;
+
+    const SYMLINKS: Materialize = Materialize {
+        link: Link::Symlink,
+        depth: 1,
+    };
+
+    fn tags(tags: &[&str]) -> BTreeSet<Tag> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    /// The paths listed in the manifest of `dest`
+    fn manifest(dest: &Path) -> Vec<String> {
+        let manifest = fs::read_to_string(dest.join(MANIFEST)).unwrap();
+        manifest.lines().map(String::from).collect()
+    }
+
+    /// A database with the files of `dir/source` and their tags
+    fn library(dir: &TestDir) -> TagsFsDb {
+        let db = TagsFsDb::in_memory();
+        for (file, tags) in [
+            ("a.jpg", &["italy", "rome"][..]),
+            ("b.jpg", &["italy"]),
+            ("c.jpg", &["spain"]),
+        ] {
+            dir.touch(&format!("source/{file}"));
+            db.add_tags_to_file(tags, file).unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn first_run_links_the_files_of_the_query() {
+        let dir = TestDir::new("materialize-first");
+        let db = library(&dir);
+        let dest = dir.join("dest");
+        dir.touch("dest/notes.txt");
+        let changes = SYMLINKS
+            .run(&db, &dir.join("source"), &tags(&["italy"]), &dest)
+            .unwrap();
+        assert_eq!(
+            (changes.added, changes.removed, changes.unchanged),
+            (3, 0, 0)
+        );
+        assert_eq!(manifest(&dest), ["a.jpg", "b.jpg", "rome/a.jpg"]);
+        assert_eq!(
+            fs::read_link(dest.join("rome/a.jpg")).unwrap(),
+            dir.join("source/a.jpg")
+        );
+        assert!(!dest.join("c.jpg").exists());
+        assert!(dest.join("notes.txt").is_file());
+    }
+
+    #[test]
+    fn re_runs_only_apply_the_differences() {
+        let dir = TestDir::new("materialize-rerun");
+        let db = library(&dir);
+        let (source, dest) = (dir.join("source"), dir.join("dest"));
+        SYMLINKS
+            .run(&db, &source, &tags(&["italy"]), &dest)
+            .unwrap();
+        db.remove_tags_from_file(["rome"], "a.jpg").unwrap();
+        db.add_tags_to_file(["rome"], "b.jpg").unwrap();
+        let changes = SYMLINKS
+            .run(&db, &source, &tags(&["italy"]), &dest)
+            .unwrap();
+        assert_eq!(
+            (changes.added, changes.removed, changes.unchanged),
+            (1, 1, 2)
+        );
+        assert_eq!(manifest(&dest), ["a.jpg", "b.jpg", "rome/b.jpg"]);
+        assert!(dest.join("rome/a.jpg").symlink_metadata().is_err());
+    }
+
+    #[test]
+    fn stale_links_are_cleaned_up() {
+        let dir = TestDir::new("materialize-stale");
+        let db = library(&dir);
+        let (source, dest) = (dir.join("source"), dir.join("dest"));
+        SYMLINKS
+            .run(&db, &source, &tags(&["italy"]), &dest)
+            .unwrap();
+        // a link removed by hand and a file which isn't a link of ours
+        fs::remove_file(dest.join("b.jpg")).unwrap();
+        db.delete_tags(&tags(&["rome"])).unwrap();
+        db.remove_tags_from_file(["italy"], "b.jpg").unwrap();
+        let changes = SYMLINKS
+            .run(&db, &source, &tags(&["italy"]), &dest)
+            .unwrap();
+        assert_eq!(
+            (changes.added, changes.removed, changes.unchanged),
+            (0, 1, 1)
+        );
+        assert_eq!(manifest(&dest), ["a.jpg"]);
+        assert!(!dest.join("rome").exists());
+
+        dir.touch("dest/b.jpg");
+        db.add_tags_to_file(["italy"], "b.jpg").unwrap();
+        let changes = SYMLINKS
+            .run(&db, &source, &tags(&["italy"]), &dest)
+            .unwrap();
+        assert_eq!(changes.added, 0);
+        assert_eq!(manifest(&dest), ["a.jpg"]);
+        assert!(fs::read_link(dest.join("b.jpg")).is_err());
+    }
+
+    #[test]
+    fn hardlinks() {
+        let dir = TestDir::new("materialize-hardlinks");
+        let db = library(&dir);
+        let (source, dest) = (dir.join("source"), dir.join("dest"));
+        let hardlinks = Materialize {
+            link: Link::Hardlink,
+            depth: 0,
+        };
+        let changes = hardlinks
+            .run(&db, &source, &tags(&["spain"]), &dest)
+            .unwrap();
+        assert_eq!(changes.added, 1);
+        let (link, file) = (dest.join("c.jpg"), source.join("c.jpg"));
+        assert_eq!(
+            link.metadata().unwrap().ino(),
+            file.metadata().unwrap().ino()
+        );
+        let changes = hardlinks
+            .run(&db, &source, &tags(&["spain"]), &dest)
+            .unwrap();
+        assert_eq!((changes.added, changes.unchanged), (0, 1));
+    }
+}
@@ -0,0 +1,227 @@
+//! Mounting tagsfs from within another program
+//!
+//! ```no_run
+//! # fn main() -> tagsfs::error::Result<()> {
+//! let mount = tagsfs::TagsFs::builder()
+//!     .db("tags.sqlite")
+//!     .source("/home/me/library")
+//!     .options([fuser::MountOption::RO])
+//!     .spawn("/mnt/tags")?;
+//! // the files are served from a background thread until the mount is dropped
+//! mount.unmount();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use fuser::{BackgroundSession, MountOption};
+
+use crate::{
+    config::{self, Config, Rules},
+    error::{Error, Result},
+    filesystem::RulesHandle,
+    TagsFs, TagsFsDb,
+};
+
+/// Settings of a mount, see [`TagsFs::builder`]
+///
+/// Everything not set here is taken from the settings stored in the database, like the command
+/// line does.
+#[derive(Debug, Default)]
+pub struct Builder {
+    db: Option<PathBuf>,
+    source: Option<PathBuf>,
+    options: Vec<MountOption>,
+    rules: Rules,
+}
+
+impl Builder {
+    /// Database with the tags, required
+    pub fn db<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.db = Some(path.into());
+        self
+    }
+
+    /// Directory with the files which are tagged, taken from the database if not given
+    pub fn source<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.source = Some(path.into());
+        self
+    }
+
+    /// Mount options on top of the stored ones, replacing the stored ones they conflict with
+    /// like [`MountOption::RW`] does [`MountOption::RO`], see [`config::replaces`]
+    ///
+    /// [`MountOption::RO`] also opens the database read-only.
+    pub fn options<I: IntoIterator<Item = MountOption>>(mut self, options: I) -> Self {
+        self.options.extend(options);
+        self
+    }
+
+    /// Rules overriding the stored ones, the ones left `None` keep their stored value
+    pub fn rules(mut self, rules: Rules) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Mount at `mountpoint` and serve the files from a background thread
+    pub fn spawn<P: AsRef<Path>>(self, mountpoint: P) -> Result<Mount> {
+        let (fs, options) = self.build()?;
+        let rules = fs.rules_handle();
+        let session = fuser::Session::new(fs, mountpoint.as_ref(), &options)?.spawn()?;
+        Ok(Mount {
+            mountpoint: mountpoint.as_ref().to_path_buf(),
+            rules,
+            session,
+        })
+    }
+
+    /// The file system and its mount options, the settings of `self` override the stored ones
+    fn build(self) -> Result<(TagsFs, Vec<MountOption>)> {
+        let path = self.db.ok_or_else(|| Error::MissingSetting("db".into()))?;
+        // the stored settings are read without writing, a read-only mount never writes at all
+        let stored_db = TagsFsDb::open_read_only(&path)?;
+        let stored = Config::from_db(&stored_db)?;
+        let replaced = |stored: &MountOption| {
+            self.options
+                .iter()
+                .any(|option| config::replaces(option, stored))
+        };
+        let stored_options: Vec<_> = stored
+            .mount_options()
+            .into_iter()
+            .filter(|option| !replaced(option))
+            .collect();
+        let options: Vec<_> = stored_options.into_iter().chain(self.options).collect();
+        let db = if options.contains(&MountOption::RO) {
+            stored_db
+        } else {
+            TagsFsDb::new(&path)?
+        };
+        let source = self
+            .source
+            .or(stored.source)
+            .ok_or_else(|| Error::MissingSetting("source".into()))?;
+        let mut fs = TagsFs::from_db(db, source);
+        self.rules.or(stored.rules).apply(&mut fs);
+        Ok((fs, options))
+    }
+}
+
+/// A running mount, unmounted when dropped
+pub struct Mount {
+    mountpoint: PathBuf,
+    rules: RulesHandle,
+    session: BackgroundSession,
+}
+
+impl Mount {
+    /// Where the files are served
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Switch to other rules without remounting
+    pub fn replace_rules(&self, rules: Rules) {
+        self.rules.replace(rules);
+    }
+
+    /// Unmount right away instead of when the handle goes out of scope
+    pub fn unmount(self) {
+        drop(self.session);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{database::base_schema, TestDir};
+
+    /// A database in `dir` with the stored settings `settings`
+    fn db(dir: &Path, settings: &[(&str, &str)]) -> PathBuf {
+        let path = dir.join("tags.sqlite");
+        rusqlite::Connection::open(&path)
+            .unwrap()
+            .execute_batch(&base_schema())
+            .unwrap();
+        let db = TagsFsDb::new(&path).unwrap();
+        for (key, value) in settings {
+            db.set_setting(key, value).unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn starts_from_the_stored_settings() {
+        let dir = TestDir::new("mount-stored");
+        let path = db(
+            &dir,
+            &[
+                ("source", "/library"),
+                ("mount-options", "allow_other"),
+                ("rules.rmdir-untags", "true"),
+            ],
+        );
+        let (fs, options) = TagsFs::builder().db(path).build().unwrap();
+        assert_eq!(fs.source, Path::new("/library"));
+        assert!(fs.rmdir_untags);
+        assert!(!fs.read_only);
+        assert!(options.contains(&MountOption::AllowOther));
+        assert!(options.contains(&MountOption::FSName("tagsfs".into())));
+    }
+
+    #[test]
+    fn settings_of_the_builder_win() {
+        let dir = TestDir::new("mount-overrides");
+        let path = db(
+            &dir,
+            &[
+                ("source", "/library"),
+                ("rules.rmdir-untags", "true"),
+                ("rules.sync-xdg-tags", "true"),
+            ],
+        );
+        let (fs, options) = TagsFs::builder()
+            .db(path)
+            .source("/elsewhere")
+            .options([MountOption::RO])
+            .rules(Rules {
+                sync_xdg_tags: Some(false),
+                ..Rules::default()
+            })
+            .build()
+            .unwrap();
+        assert_eq!(fs.source, Path::new("/elsewhere"));
+        // only the rules set on the builder change
+        assert!(!fs.xdg_sync);
+        assert!(fs.rmdir_untags);
+        assert!(fs.read_only);
+        assert!(options.contains(&MountOption::RO));
+    }
+
+    #[test]
+    fn options_of_the_builder_replace_their_stored_counterparts() {
+        let dir = TestDir::new("mount-options");
+        let path = db(
+            &dir,
+            &[("source", "/library"), ("mount-options", "ro,allow_other")],
+        );
+        let (fs, options) = TagsFs::builder()
+            .db(path)
+            .options([MountOption::RW])
+            .build()
+            .unwrap();
+        assert!(!fs.read_only);
+        assert!(!options.contains(&MountOption::RO));
+        assert!(options.contains(&MountOption::RW));
+        assert!(options.contains(&MountOption::AllowOther));
+    }
+
+    #[test]
+    fn needs_a_database_and_a_source() {
+        assert!(TagsFs::builder().source("/library").build().is_err());
+        let dir = TestDir::new("mount-no-source");
+        let path = db(&dir, &[]);
+        assert!(TagsFs::builder().db(path).build().is_err());
+    }
+}
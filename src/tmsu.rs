@@ -0,0 +1,321 @@
+//! Import of the tags of a [TMSU](https://tmsu.org) database
+//!
+//! TMSU tags with a value become a single tag `tag=value` and implications are resolved into
+//! the implied tags since tagsfs has no implications of its own. Files are matched by their path
+//! relative to the TMSU root: a file directly in the root is taken to be the file of the same name
+//! in `source`. Files in subdirectories have to be brought into `source` with a tree import
+//! first, they are found by the path they were imported from. Others are reported as unmappable.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::Path,
+};
+
+use rusqlite::{Connection, OpenFlags};
+
+use crate::{error::Result, Tag, TagsFsDb};
+
+/// What an import did or would do in a dry run
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Number of files which got tags
+    pub files: usize,
+    /// Tags which were added to at least one file
+    pub tags: BTreeSet<Tag>,
+    /// Number of tags added to files
+    pub taggings: usize,
+    pub unmappable: Vec<Unmappable>,
+}
+
+/// An entry of the TMSU database which can't be represented in tagsfs
+#[derive(Debug)]
+pub struct Unmappable {
+    pub entry: String,
+    pub reason: &'static str,
+}
+
+/// A tag and its value in TMSU, value `0` stands for no value
+type TagValue = (u64, u64);
+
+/// Import the TMSU database `tmsu` into `db`
+///
+/// `root` is the directory TMSU stores relative paths against, by default the directory which
+/// contains the `.tmsu` directory with the database. With `dry_run` only the report is created.
+pub fn import(
+    db: &TagsFsDb,
+    tmsu: &Path,
+    root: Option<&Path>,
+    source: &Path,
+    dry_run: bool,
+) -> Result<Report> {
+    let conn = Connection::open_with_flags(tmsu, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let root = match root {
+        Some(root) => root.to_path_buf(),
+        None => tmsu
+            .parent()
+            .and_then(Path::parent)
+            .unwrap_or_else(|| Path::new("."))
+            .to_path_buf(),
+    };
+    let root = root.canonicalize().unwrap_or(root);
+    let source = source.canonicalize()?;
+    let relative = |path: &Path| {
+        path.strip_prefix(&root)
+            .or_else(|_| path.strip_prefix(&source))
+            .ok()
+            .map(Path::to_path_buf)
+    };
+
+    let tags = names(&conn, "tag")?;
+    let values = names(&conn, "value")?;
+    let implications = implications(&conn)?;
+    let mut report = Report::default();
+
+    let mut files = conn.prepare("SELECT id, directory, name, is_dir FROM file ORDER BY id")?;
+    let files = files
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, u64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, bool>(3)?,
+            ))
+        })?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let mut file_tags = conn.prepare("SELECT tag_id, value_id FROM file_tag WHERE file_id = ?")?;
+
+    db.transaction(|db| {
+        for (id, directory, name, is_dir) in files {
+            // TMSU stores directories relative to the root, except for ones outside of it
+            let directory = root.join(&directory);
+            let path = directory.canonicalize().unwrap_or(directory).join(&name);
+            let unmappable = |reason| Unmappable {
+                entry: path.display().to_string(),
+                reason,
+            };
+            if is_dir {
+                report
+                    .unmappable
+                    .push(unmappable("directories can't be tagged"));
+                continue;
+            }
+            let relative = match relative(&path) {
+                Some(relative) => relative,
+                None => {
+                    report
+                        .unmappable
+                        .push(unmappable("outside of the TMSU root and source"));
+                    continue;
+                }
+            };
+            let name = if relative.parent() == Some(Path::new("")) {
+                name
+            } else {
+                match db.file_with_origin(&path.to_string_lossy())? {
+                    Some(name) => name,
+                    None => {
+                        report
+                            .unmappable
+                            .push(unmappable("in a subdirectory, import the tree first"));
+                        continue;
+                    }
+                }
+            };
+            if !source.join(&name).is_file() {
+                report.unmappable.push(unmappable("missing in source"));
+                continue;
+            }
+
+            let tagged = file_tags
+                .query_map([id], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<std::result::Result<BTreeSet<TagValue>, _>>()?;
+            let mut new_tags = BTreeSet::new();
+            for (tag_id, value_id) in resolve_implications(tagged, &implications) {
+                let tag = match (tags.get(&tag_id), value_id) {
+                    (Some(tag), 0) => tag.clone(),
+                    (Some(tag), value_id) => match values.get(&value_id) {
+                        Some(value) => format!("{tag}={value}"),
+                        None => {
+                            report.unmappable.push(Unmappable {
+                                entry: format!("{tag} on {}", path.display()),
+                                reason: "unknown value",
+                            });
+                            continue;
+                        }
+                    },
+                    (None, _) => {
+                        report.unmappable.push(Unmappable {
+                            entry: format!("tag {tag_id} on {}", path.display()),
+                            reason: "unknown tag",
+                        });
+                        continue;
+                    }
+                };
+                if tag.contains('/') {
+                    report.unmappable.push(Unmappable {
+                        entry: format!("{tag} on {}", path.display()),
+                        reason: "tags can't contain '/'",
+                    });
+                    continue;
+                }
+                new_tags.insert(tag);
+            }
+            let current = db.file_tags(&name)?;
+            let added: Vec<_> = new_tags.difference(&current).cloned().collect();
+            if added.is_empty() {
+                continue;
+            }
+            if !dry_run {
+                db.add_tags_to_file(&added, &name)?;
+            }
+            report.files += 1;
+            report.taggings += added.len();
+            report.tags.extend(added);
+        }
+        Ok(())
+    })?;
+    Ok(report)
+}
+
+/// Names of the `tag` or `value` table by id
+fn names(conn: &Connection, table: &str) -> Result<HashMap<u64, String>> {
+    let mut stmt = conn.prepare(&format!("SELECT id, name FROM {table}"))?;
+    let names = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<std::result::Result<_, _>>()?;
+    Ok(names)
+}
+
+fn implications(conn: &Connection) -> Result<Vec<(TagValue, TagValue)>> {
+    let mut stmt =
+        conn.prepare("SELECT tag_id, value_id, implied_tag_id, implied_value_id FROM implication")?;
+    let implications = stmt
+        .query_map([], |row| {
+            Ok(((row.get(0)?, row.get(1)?), (row.get(2)?, row.get(3)?)))
+        })?
+        .collect::<std::result::Result<_, _>>()?;
+    Ok(implications)
+}
+
+/// Add everything `tags` imply until nothing new is implied
+///
+/// An implication without a value applies to the tag with any value.
+fn resolve_implications(
+    mut tags: BTreeSet<TagValue>,
+    implications: &[(TagValue, TagValue)],
+) -> BTreeSet<TagValue> {
+    loop {
+        let implied: Vec<_> = implications
+            .iter()
+            .filter(|((tag, value), _)| {
+                tags.iter()
+                    .any(|(t, v)| t == tag && (*value == 0 || v == value))
+            })
+            .map(|(_, implied)| *implied)
+            .filter(|implied| !tags.contains(implied))
+            .collect();
+        if implied.is_empty() {
+            return tags;
+        }
+        tags.extend(implied);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestDir;
+
+    /// Directory, name and tags of a file in TMSU
+    type File<'a> = (&'a str, &'a str, &'a [TagValue]);
+
+    /// A TMSU database in `.tmsu/db` of `root` with the files and tags of `files`
+    fn tmsu_db(root: &Path, files: &[File]) -> std::path::PathBuf {
+        let path = root.join(".tmsu").join("db");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE tag (id INTEGER PRIMARY KEY, name TEXT); \
+             CREATE TABLE value (id INTEGER PRIMARY KEY, name TEXT); \
+             CREATE TABLE file (\
+                 id INTEGER PRIMARY KEY, directory TEXT, name TEXT, is_dir BOOLEAN\
+             ); \
+             CREATE TABLE file_tag (file_id INTEGER, tag_id INTEGER, value_id INTEGER); \
+             CREATE TABLE implication (\
+                 tag_id INTEGER, value_id INTEGER, implied_tag_id INTEGER, implied_value_id INTEGER\
+             ); \
+             INSERT INTO tag VALUES (1, 'music'), (2, 'year'), (3, 'media'); \
+             INSERT INTO value VALUES (1, '1999'); \
+             INSERT INTO implication VALUES (1, 0, 3, 0);",
+        )
+        .unwrap();
+        for (id, (directory, name, tags)) in files.iter().enumerate() {
+            conn.execute(
+                "INSERT INTO file VALUES (?, ?, ?, 0)",
+                rusqlite::params![id, directory, name],
+            )
+            .unwrap();
+            for (tag, value) in tags.iter() {
+                conn.execute(
+                    "INSERT INTO file_tag VALUES (?, ?, ?)",
+                    rusqlite::params![id, tag, value],
+                )
+                .unwrap();
+            }
+        }
+        path
+    }
+
+    #[test]
+    fn imports_files_of_the_root() {
+        let dir = TestDir::new("tmsu-root");
+        dir.touch("source/song.mp3");
+        let tmsu = tmsu_db(&dir, &[(".", "song.mp3", &[(1, 0), (2, 1)])]);
+        let db = TagsFsDb::in_memory();
+        let report = import(&db, &tmsu, None, &dir.join("source"), false).unwrap();
+        assert!(report.unmappable.is_empty(), "{:?}", report.unmappable);
+        assert_eq!(report.files, 1);
+        let expected = ["media", "music", "year=1999"].map(String::from);
+        assert_eq!(db.file_tags("song.mp3").unwrap(), BTreeSet::from(expected));
+    }
+
+    #[test]
+    fn imports_files_of_subdirectories_by_origin() {
+        let dir = TestDir::new("tmsu-subdirectory");
+        dir.touch("source/song.mp3");
+        let origin = dir.touch("albums/1999/song.mp3");
+        let stray = dir.touch("albums/2000/stray.mp3");
+        let tmsu = tmsu_db(
+            &dir,
+            &[
+                ("albums/1999", "song.mp3", &[(1, 0)]),
+                ("albums/2000", "stray.mp3", &[(1, 0)]),
+            ],
+        );
+        let db = TagsFsDb::in_memory();
+        db.set_origin("song.mp3", &origin.to_string_lossy())
+            .unwrap();
+        let report = import(&db, &tmsu, None, &dir.join("source"), true).unwrap();
+        assert_eq!(report.files, 1);
+        let report = import(&db, &tmsu, None, &dir.join("source"), false).unwrap();
+        assert_eq!(report.files, 1);
+        assert!(db.file_tags("song.mp3").unwrap().contains("music"));
+        let unmappable: Vec<_> = report.unmappable.iter().map(|u| &u.entry).collect();
+        assert_eq!(unmappable, [&stray.display().to_string()]);
+    }
+
+    #[test]
+    fn dry_run_changes_nothing() {
+        let dir = TestDir::new("tmsu-dry-run");
+        dir.touch("source/song.mp3");
+        let tmsu = tmsu_db(
+            &dir,
+            &[(".", "song.mp3", &[(1, 0)]), (".", "gone.mp3", &[])],
+        );
+        let db = TagsFsDb::in_memory();
+        let report = import(&db, &tmsu, None, &dir.join("source"), true).unwrap();
+        assert_eq!(report.taggings, 2);
+        assert_eq!(report.unmappable.len(), 1);
+        assert!(db.file_tags("song.mp3").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,229 @@
+//! Import of a directory tree, turning the directories a file is in into its tags
+//!
+//! `Photos/2019/Italy/Rome/IMG_0001.jpg` becomes `IMG_0001.jpg` in `source` with the tags
+//! `Photos`, `2019`, `Italy` and `Rome`, less the ones filtered out. The path the file came from
+//! is kept in the database and shown as the `user.tagsfs.origin` attribute.
+
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{error::Result, filesystem::wildcard_match, Tag, TagsFsDb};
+
+/// How files get into `source`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transfer {
+    /// Move the file, copying it if it is on another file system
+    Move,
+    /// Leave the file where it is and register it with a symlink in `source`
+    Symlink,
+}
+
+#[derive(Debug, Clone)]
+pub struct TreeImport {
+    pub transfer: Transfer,
+    /// Patterns (`*` and `?` wildcards) of directory names which don't become tags
+    pub skip: Vec<String>,
+    /// Tags to use instead of some directory names, an empty tag drops the directory
+    pub rename: HashMap<String, Tag>,
+}
+
+/// A file of the tree and what it becomes
+#[derive(Debug)]
+pub struct Planned {
+    pub from: PathBuf,
+    /// Name in `source`, differs from the original name when that is taken already
+    pub name: String,
+    pub tags: BTreeSet<Tag>,
+}
+
+impl TreeImport {
+    /// What importing the files below `root` into `source` would do
+    pub fn plan(&self, root: &Path, source: &Path) -> Result<Vec<Planned>> {
+        let mut files = Vec::new();
+        walk(root, &mut files)?;
+        files.sort();
+        let mut taken = HashSet::new();
+        let mut planned = Vec::new();
+        for from in files {
+            // already where it belongs
+            if from.parent() == Some(source) {
+                continue;
+            }
+            let relative = from.strip_prefix(root).unwrap_or(&from);
+            let tags = relative
+                .parent()
+                .into_iter()
+                .flat_map(Path::iter)
+                .filter_map(|component| self.tag(&component.to_string_lossy()))
+                .collect();
+            let file_name = from
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let name = unique_name(&file_name, |name| {
+                taken.contains(name) || source.join(name).symlink_metadata().is_ok()
+            });
+            taken.insert(name.clone());
+            planned.push(Planned { from, name, tags });
+        }
+        Ok(planned)
+    }
+
+    /// Bring the `planned` files into `source` and tag them
+    pub fn apply(&self, db: &TagsFsDb, planned: &[Planned], source: &Path) -> Result<()> {
+        for file in planned {
+            let target = source.join(&file.name);
+            let origin = file.from.canonicalize()?;
+            match self.transfer {
+                Transfer::Move => move_file(&file.from, &target)?,
+                Transfer::Symlink => std::os::unix::fs::symlink(&origin, &target)?,
+            }
+            db.transaction(|db| {
+                let current = db.file_tags(&file.name)?;
+                db.add_tags_to_file(file.tags.difference(&current), &file.name)?;
+                db.set_origin(&file.name, &origin.to_string_lossy())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// The tag for the directory `component`, if any
+    fn tag(&self, component: &str) -> Option<Tag> {
+        if self
+            .skip
+            .iter()
+            .any(|pattern| wildcard_match(pattern.as_bytes(), component.as_bytes()))
+        {
+            return None;
+        }
+        let tag = self
+            .rename
+            .get(component)
+            .cloned()
+            .unwrap_or_else(|| component.to_string());
+        (!tag.is_empty()).then_some(tag)
+    }
+}
+
+/// All regular files below `dir`, symlinks aren't followed
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// `name` or, if `taken`, `name` with a number inserted before the extension
+fn unique_name(name: &str, taken: impl Fn(&str) -> bool) -> String {
+    if !taken(name) {
+        return name.to_string();
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{extension}")),
+        _ => (name, String::new()),
+    };
+    (2..)
+        .map(|n| format!("{stem}.{n}{extension}"))
+        .find(|name| !taken(name))
+        .expect("there are enough numbers")
+}
+
+fn move_file(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.raw_os_error() == Some(libc::EXDEV) => {
+            fs::copy(from, to)?;
+            fs::remove_file(from)?;
+            Ok(())
+        }
+        result => Ok(result?),
+    }
+}
+
+impl Planned {
+    /// Whether the file gets another name in `source` because its own is taken
+    pub fn renamed(&self) -> bool {
+        self.from.file_name() != Some(OsStr::new(&self.name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TestDir;
+
+    fn import(transfer: Transfer) -> TreeImport {
+        TreeImport {
+            transfer,
+            skip: vec!["20??".into()],
+            rename: HashMap::from([
+                ("Photos".to_string(), "photo".to_string()),
+                ("misc".to_string(), String::new()),
+            ]),
+        }
+    }
+
+    #[test]
+    fn plans_tags_and_names() {
+        let dir = TestDir::new("tree-plan");
+        dir.touch("tree/Photos/2019/Italy/rome.jpg");
+        dir.touch("tree/misc/rome.jpg");
+        dir.touch("source/rome.2.jpg");
+        let planned = import(Transfer::Move)
+            .plan(&dir.join("tree"), &dir.join("source"))
+            .unwrap();
+        let planned: Vec<_> = planned
+            .iter()
+            .map(|file| (file.name.as_str(), file.tags.iter().cloned().collect()))
+            .collect();
+        let expected: Vec<(&str, Vec<Tag>)> = vec![
+            ("rome.jpg", vec!["Italy".into(), "photo".into()]),
+            ("rome.3.jpg", vec![]),
+        ];
+        assert_eq!(planned, expected);
+    }
+
+    #[test]
+    fn moves_files_and_keeps_their_origin() {
+        let dir = TestDir::new("tree-move");
+        let from = dir.touch("tree/Italy/rome.jpg");
+        std::fs::create_dir(dir.join("source")).unwrap();
+        let db = TagsFsDb::in_memory();
+        let import = import(Transfer::Move);
+        let planned = import.plan(&dir.join("tree"), &dir.join("source")).unwrap();
+        import.apply(&db, &planned, &dir.join("source")).unwrap();
+        assert!(!from.exists());
+        assert!(dir.join("source/rome.jpg").is_file());
+        assert!(db.file_tags("rome.jpg").unwrap().contains("Italy"));
+        assert_eq!(
+            db.origin("rome.jpg").unwrap(),
+            Some(from.to_string_lossy().to_string())
+        );
+    }
+
+    #[test]
+    fn symlinks_files() {
+        let dir = TestDir::new("tree-symlink");
+        let from = dir.touch("tree/Italy/rome.jpg");
+        std::fs::create_dir(dir.join("source")).unwrap();
+        let db = TagsFsDb::in_memory();
+        let import = import(Transfer::Symlink);
+        let planned = import.plan(&dir.join("tree"), &dir.join("source")).unwrap();
+        import.apply(&db, &planned, &dir.join("source")).unwrap();
+        assert!(from.is_file());
+        assert_eq!(
+            std::fs::read_link(dir.join("source/rome.jpg")).unwrap(),
+            from
+        );
+        assert!(db.file_tags("rome.jpg").unwrap().contains("Italy"));
+    }
+}
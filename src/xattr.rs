@@ -0,0 +1,131 @@
+//! Extended attributes of backing files
+//!
+//! None of the functions follow symlinks, the file system only ever shows regular files.
+
+use std::{
+    collections::BTreeSet,
+    ffi::{CString, OsStr},
+    os::unix::prelude::OsStrExt,
+    path::Path,
+};
+
+use log::warn;
+
+use crate::{
+    error::{Error, Result},
+    filesystem, Tag,
+};
+
+/// Tags as stored by KDE Baloo and other freedesktop tools, separated by commas
+pub const XDG_TAGS: &str = "user.xdg.tags";
+
+pub(crate) fn c_string(s: &OsStr) -> CString {
+    // neither paths nor attribute names handed to us by the kernel contain nul bytes
+    unsafe { CString::from_vec_unchecked(s.as_bytes().to_vec()) }
+}
+
+/// Query the size of an extended attribute value or list and read it, `read` is called with a
+/// buffer and its size like `lgetxattr` and `llistxattr`
+fn read_xattr_buffer(mut read: impl FnMut(*mut libc::c_void, usize) -> isize) -> Result<Vec<u8>> {
+    loop {
+        let size = read(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(Error::last_os_error());
+        }
+        let mut buf = vec![0u8; size as usize];
+        let len = read(buf.as_mut_ptr().cast(), buf.len());
+        if len >= 0 {
+            buf.truncate(len as usize);
+            return Ok(buf);
+        }
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err.into());
+        }
+        // the value grew since we asked for its size, try again
+    }
+}
+
+pub fn get(path: &Path, name: &OsStr) -> Result<Vec<u8>> {
+    let c_path = c_string(path.as_os_str());
+    let c_name = c_string(name);
+    read_xattr_buffer(|buf, size| unsafe {
+        libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), buf, size)
+    })
+}
+
+pub fn list(path: &Path) -> Result<Vec<u8>> {
+    let c_path = c_string(path.as_os_str());
+    read_xattr_buffer(|buf, size| unsafe { libc::llistxattr(c_path.as_ptr(), buf.cast(), size) })
+}
+
+pub fn set(path: &Path, name: &OsStr, value: &[u8], flags: i32) -> Result<()> {
+    let c_path = c_string(path.as_os_str());
+    let c_name = c_string(name);
+    let err = unsafe {
+        libc::lsetxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            flags,
+        )
+    };
+    if err != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn remove(path: &Path, name: &OsStr) -> Result<()> {
+    let c_path = c_string(path.as_os_str());
+    let c_name = c_string(name);
+    let err = unsafe { libc::lremovexattr(c_path.as_ptr(), c_name.as_ptr()) };
+    if err != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Tags of the freedesktop `user.xdg.tags` attribute of `path`, `None` if it isn't set
+///
+/// Tags which can't be used as a directory name are skipped, like the ones `user.tags` rejects.
+pub fn read_xdg_tags(path: &Path) -> Result<Option<BTreeSet<Tag>>> {
+    let value = match get(path, OsStr::new(XDG_TAGS)) {
+        Ok(value) => value,
+        Err(err) if err.errno() == libc::ENODATA || err.errno() == libc::ENOTSUP => {
+            return Ok(None)
+        }
+        Err(err) => return Err(err),
+    };
+    let tags = String::from_utf8_lossy(&value)
+        .split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .filter(|tag| {
+            let valid = filesystem::is_valid_tag(tag);
+            if !valid {
+                warn!("skipping tag {tag:?} of {path:?}, it isn't a valid directory name");
+            }
+            valid
+        })
+        .map(String::from)
+        .collect();
+    Ok(Some(tags))
+}
+
+/// Store `tags` in the freedesktop `user.xdg.tags` attribute of `path`
+pub fn write_xdg_tags(path: &Path, tags: &BTreeSet<Tag>) -> Result<()> {
+    if tags.is_empty() {
+        return match remove(path, OsStr::new(XDG_TAGS)) {
+            Err(err) if err.errno() != libc::ENODATA => Err(err),
+            _ => Ok(()),
+        };
+    }
+    let value = tags
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(",");
+    set(path, OsStr::new(XDG_TAGS), value.as_bytes(), 0)
+}